@@ -0,0 +1,431 @@
+//! Processing of recorded samples, independent of the GUI.
+
+/// Splits `(field, polarization)` samples into field cycles.
+/// A new cycle starts when the field becomes non-negative after being negative;
+/// neighbouring cycles share one sample so that lines stay continuous.
+pub fn split_cycles(samples: &[(f64, f64)]) -> Vec<&[(f64, f64)]> {
+    let mut cycles = vec![];
+    let mut start = 0;
+    for i in 1..samples.len() {
+        if samples[i - 1].0 < 0.0 && samples[i].0 >= 0.0 {
+            cycles.push(&samples[start..=i]);
+            start = i;
+        }
+    }
+    if start < samples.len() {
+        cycles.push(&samples[start..]);
+    }
+    cycles
+}
+
+/// Characteristic points of a P–E loop
+#[derive(Debug, Default, Clone)]
+pub struct LoopMetrics {
+    /// Fields where polarization crossed [`LoopMetrics::level`] while growing
+    pub coercive_up: Vec<f64>,
+    /// Fields where polarization crossed [`LoopMetrics::level`] while decreasing
+    pub coercive_down: Vec<f64>,
+    /// Polarization when the field crossed zero while decreasing
+    pub remanent_up: Vec<f64>,
+    /// Polarization when the field crossed zero while growing
+    pub remanent_down: Vec<f64>,
+    /// Polarization where the coercive fields are read: midway between the extremes of the samples given to
+    /// [`LoopMetrics::new`], [`LoopMetrics::extend`] keeps its own
+    pub level: f64,
+}
+
+impl LoopMetrics {
+    /// Finds crossings of zero field and of the middle polarization in `(field, polarization)` samples
+    pub fn new(samples: &[(f64, f64)]) -> Self {
+        let (low, high) = samples
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(l, h), &(_, p)| {
+                (l.min(p), h.max(p))
+            });
+        let mut m = Self {
+            level: (low + high) / 2.0,
+            ..Self::default()
+        };
+        for w in samples.windows(2) {
+            let ((e1, p1), (e2, p2)) = (w[0], w[1]);
+            let (q1, q2) = (p1 - m.level, p2 - m.level);
+
+            if q1 < 0.0 && q2 >= 0.0 {
+                m.coercive_up.push(interpolate(q1, e1, q2, e2));
+            } else if q1 > 0.0 && q2 <= 0.0 {
+                m.coercive_down.push(interpolate(q1, e1, q2, e2));
+            }
+
+            if e1 > 0.0 && e2 <= 0.0 {
+                m.remanent_up.push(interpolate(e1, p1, e2, p2));
+            } else if e1 < 0.0 && e2 >= 0.0 {
+                m.remanent_down.push(interpolate(e1, p1, e2, p2));
+            }
+        }
+        m
+    }
+
+    /// Horizontal offset of the loop: midpoint of the mean coercive fields, `None` without both of them
+    pub fn imprint(&self) -> Option<f64> {
+        Some((mean(&self.coercive_up)? + mean(&self.coercive_down)?) / 2.0)
+    }
+
+    /// Adds crossings found in another part of the record
+    pub fn extend(&mut self, other: Self) {
+        self.coercive_up.extend(other.coercive_up);
+        self.coercive_down.extend(other.coercive_down);
+        self.remanent_up.extend(other.remanent_up);
+        self.remanent_down.extend(other.remanent_down);
+    }
+}
+
+/// Value of `y` where the line through `(x1, y1)` and `(x2, y2)` crosses `x = 0`
+fn interpolate(x1: f64, y1: f64, x2: f64, y2: f64) -> f64 {
+    if x1 == x2 {
+        return y2;
+    }
+    y1 + (y2 - y1) * (-x1) / (x2 - x1)
+}
+
+/// Average of `v`, `None` if it is empty
+pub fn mean(v: &[f64]) -> Option<f64> {
+    if v.is_empty() {
+        None
+    } else {
+        Some(v.iter().sum::<f64>() / v.len() as f64)
+    }
+}
+
+/// Centered moving average over `2*half_width + 1` samples (fewer at the edges)
+pub fn smooth(v: &[f64], half_width: usize) -> Vec<f64> {
+    if half_width == 0 {
+        return v.to_vec();
+    }
+    let mut prefix = Vec::with_capacity(v.len() + 1);
+    prefix.push(0.0);
+    for x in v {
+        prefix.push(prefix.last().unwrap() + x);
+    }
+    (0..v.len())
+        .map(|i| {
+            let from = i.saturating_sub(half_width);
+            let to = (i + half_width + 1).min(v.len());
+            (prefix[to] - prefix[from]) / (to - from) as f64
+        })
+        .collect()
+}
+
+/// Switching current response to one field reversal
+#[derive(Debug, Clone)]
+pub struct SwitchingPulse {
+    /// Sample index of the reversal
+    pub start: usize,
+    /// Whether the field was reversed upwards
+    pub up: bool,
+    /// Current of the largest magnitude
+    pub peak_current: f64,
+    /// Ticks from the reversal to the peak
+    pub peak_time: usize,
+    /// Ticks from the reversal until 90% of the switched polarization, `None` if nothing switched
+    pub switching_time: Option<usize>,
+}
+
+/// Share of the switched polarization reached at the switching time
+pub const SWITCHED_SHARE: f64 = 0.9;
+
+/// Analyses current samples between consecutive reversals.
+/// `reversals` holds sample indices of the reversals (ascending) and whether the field went up.
+pub fn switching_pulses(current: &[f64], reversals: &[(usize, bool)]) -> Vec<SwitchingPulse> {
+    let mut pulses = vec![];
+    for (k, &(start, up)) in reversals.iter().enumerate() {
+        let end = reversals
+            .get(k + 1)
+            .map_or(current.len(), |r| r.0)
+            .min(current.len());
+        if start >= end {
+            continue;
+        }
+        let segment = &current[start..end];
+
+        let (peak_time, &peak_current) = segment
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+            .unwrap();
+
+        let total: f64 = segment.iter().sum();
+        let switching_time = if total == 0.0 {
+            None
+        } else {
+            let mut accum = 0.0;
+            segment.iter().position(|i| {
+                accum += i;
+                accum / total >= SWITCHED_SHARE
+            })
+        };
+
+        pulses.push(SwitchingPulse {
+            start,
+            up,
+            peak_current,
+            peak_time,
+            switching_time,
+        });
+    }
+    pulses
+}
+
+/// Pulse of a Positive-Up-Negative-Down sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PundPulse {
+    /// Positive pulse after a negative one: switching and non-switching response
+    P,
+    /// Positive pulse after a positive one: non-switching response only
+    U,
+    /// Negative pulse after a positive one
+    N,
+    /// Negative pulse after a negative one
+    D,
+}
+
+/// Polarization change caused by one pulse
+#[derive(Debug, Clone)]
+pub struct PundResponse {
+    /// Name of the pulse
+    pub pulse: PundPulse,
+    /// Sample index where the pulse starts
+    pub start: usize,
+    /// Change during the pulse
+    pub during: f64,
+    /// Change from the pulse start until the next pulse (includes relaxation in the delay)
+    pub remanent: f64,
+}
+
+/// Splits `(field, polarization)` samples into pulses (runs where field differs from `baseline`)
+/// and names them by their sign and the sign of the previous pulse. Samples start from negative polarization.
+pub fn pund_responses(samples: &[(f64, f64)], baseline: f64) -> Vec<PundResponse> {
+    let sign = |e: f64| {
+        if e > baseline {
+            1
+        } else if e < baseline {
+            -1
+        } else {
+            0
+        }
+    };
+
+    // (start, end, sign) of pulses
+    let mut runs: Vec<(usize, usize, i32)> = vec![];
+    for (i, &(e, _)) in samples.iter().enumerate() {
+        let s = sign(e);
+        if s == 0 {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(r) if r.1 == i && r.2 == s => r.1 = i + 1,
+            _ => runs.push((i, i + 1, s)),
+        }
+    }
+
+    let mut prev = -1;
+    runs.iter()
+        .enumerate()
+        .map(|(k, &(start, end, s))| {
+            let pulse = match (prev, s) {
+                (-1, 1) => PundPulse::P,
+                (1, 1) => PundPulse::U,
+                (1, _) => PundPulse::N,
+                _ => PundPulse::D,
+            };
+            prev = s;
+
+            // polarization before the pulse
+            let before = if start > 0 { samples[start - 1].1 } else { 0.0 };
+            let next = runs.get(k + 1).map_or(samples.len(), |r| r.0);
+            PundResponse {
+                pulse,
+                start,
+                during: samples[end - 1].1 - before,
+                remanent: samples[next - 1].1 - before,
+            }
+        })
+        .collect()
+}
+
+/// Averages of PUND responses and the switched polarization derived from them
+#[derive(Debug, Default, Clone)]
+pub struct PundSummary {
+    /// Mean `during` change for P, U, N, D pulses
+    pub during: [Option<f64>; 4],
+    /// Mean `remanent` change for P, U, N, D pulses
+    pub remanent: [Option<f64>; 4],
+}
+
+impl PundSummary {
+    /// Averages `responses` over pulses of each name
+    pub fn new(responses: &[PundResponse]) -> Self {
+        let mut s = Self::default();
+        for (i, pulse) in [PundPulse::P, PundPulse::U, PundPulse::N, PundPulse::D]
+            .into_iter()
+            .enumerate()
+        {
+            let of = |f: fn(&PundResponse) -> f64| {
+                responses
+                    .iter()
+                    .filter(|r| r.pulse == pulse)
+                    .map(f)
+                    .collect::<Vec<_>>()
+            };
+            s.during[i] = mean(&of(|r| r.during));
+            s.remanent[i] = mean(&of(|r| r.remanent));
+        }
+        s
+    }
+
+    /// Switched polarization `P - U` for positive and `N - D` for negative pulses (from `during` changes)
+    pub fn switched(&self) -> (Option<f64>, Option<f64>) {
+        let diff = |a: Option<f64>, b: Option<f64>| a.zip(b).map(|(a, b)| a - b);
+        (
+            diff(self.during[0], self.during[1]),
+            diff(self.during[2], self.during[3]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    /// Two periods of a triangle field and a loop switching at `imprint ± coercive`
+    fn synthetic_loop(coercive: f64, imprint: f64) -> Vec<(f64, f64)> {
+        let n = 400;
+        let branch = |e: f64, centre: f64| 0.5 + 0.5 * ((e - centre) / 0.05).tanh();
+        (0..2 * n)
+            .map(|i| {
+                let phase = (i % n) as f64 / n as f64;
+                let e = if phase < 0.25 {
+                    4.0 * phase
+                } else if phase < 0.75 {
+                    2.0 - 4.0 * phase
+                } else {
+                    4.0 * phase - 4.0
+                };
+                let growing = !(0.25..0.75).contains(&phase);
+                (
+                    e,
+                    branch(
+                        e,
+                        if growing {
+                            imprint + coercive
+                        } else {
+                            imprint - coercive
+                        },
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_metrics_of_shifted_loop() {
+        let samples = synthetic_loop(0.3, 0.1);
+        let mut metrics = LoopMetrics::default();
+        for c in split_cycles(&samples) {
+            metrics.extend(LoopMetrics::new(c));
+        }
+        assert!(!metrics.coercive_up.is_empty() && !metrics.coercive_down.is_empty());
+        assert!(
+            metrics.coercive_up.iter().all(|&e| close(e, 0.4)),
+            "{:?}",
+            metrics.coercive_up
+        );
+        assert!(
+            metrics.coercive_down.iter().all(|&e| close(e, -0.2)),
+            "{:?}",
+            metrics.coercive_down
+        );
+        assert!(close(metrics.imprint().unwrap(), 0.1));
+        assert!(metrics.remanent_up.iter().all(|&p| p > 0.9));
+        assert!(metrics.remanent_down.iter().all(|&p| p < 0.1));
+    }
+
+    #[test]
+    fn loop_metrics_of_unsaturated_loop() {
+        let samples: Vec<(f64, f64)> = synthetic_loop(0.3, 0.1)
+            .into_iter()
+            .map(|(e, p)| (e, 0.2 + 0.3 * p))
+            .collect();
+        let metrics = LoopMetrics::new(&samples);
+        assert!(close(metrics.level, 0.35));
+        assert!(metrics.coercive_up.iter().all(|&e| close(e, 0.4)));
+        assert!(close(metrics.imprint().unwrap(), 0.1));
+    }
+
+    #[test]
+    fn loop_metrics_without_switching() {
+        let samples: Vec<(f64, f64)> = synthetic_loop(0.3, 0.0)
+            .into_iter()
+            .map(|(e, _)| (e, 0.0))
+            .collect();
+        let metrics = LoopMetrics::new(&samples);
+        assert!(metrics.coercive_up.is_empty() && metrics.coercive_down.is_empty());
+        assert_eq!(metrics.imprint(), None);
+    }
+
+    #[test]
+    fn split_cycles_share_samples() {
+        let samples = [(-1.0, 0.0), (1.0, 0.0), (-1.0, 0.0), (0.0, 0.0), (1.0, 0.0)];
+        let cycles = split_cycles(&samples);
+        assert_eq!(cycles, [&samples[0..2], &samples[1..4], &samples[3..]]);
+    }
+
+    #[test]
+    fn pund_sequence() {
+        // each pulse lasts 3 samples, adds 0.1 of dielectric response that relaxes in the 2-sample delay
+        let mut samples = vec![];
+        for (e, pulse, delay) in [
+            (1.0, [0.6, 1.1, 1.1], 1.0),
+            (1.0, [1.1; 3], 1.0),
+            (-1.0, [0.4, -0.1, -0.1], 0.0),
+            (-1.0, [-0.1; 3], 0.0),
+        ] {
+            samples.extend(pulse.map(|p| (e, p)));
+            samples.extend([(0.0, delay); 2]);
+        }
+
+        let responses = pund_responses(&samples, 0.0);
+        let pulses: Vec<PundPulse> = responses.iter().map(|r| r.pulse).collect();
+        assert_eq!(
+            pulses,
+            [PundPulse::P, PundPulse::U, PundPulse::N, PundPulse::D]
+        );
+        assert_eq!(
+            responses.iter().map(|r| r.start).collect::<Vec<_>>(),
+            [0, 5, 10, 15]
+        );
+        for (r, (during, remanent)) in
+            responses
+                .iter()
+                .zip([(1.1, 1.0), (0.1, 0.0), (-1.1, -1.0), (-0.1, 0.0)])
+        {
+            assert!(
+                close(r.during, during) && close(r.remanent, remanent),
+                "{r:?}"
+            );
+        }
+
+        let (up, down) = PundSummary::new(&responses).switched();
+        assert!(close(up.unwrap(), 1.0) && close(down.unwrap(), -1.0));
+    }
+
+    #[test]
+    fn smoothing_window_shrinks_at_edges() {
+        let v = [0.0, 3.0, 0.0, 3.0, 0.0];
+        assert_eq!(smooth(&v, 0), v);
+        assert_eq!(smooth(&v, 1), [1.5, 1.0, 2.0, 1.0, 1.5]);
+    }
+}
@@ -1,10 +1,71 @@
-use std::vec;
+use std::{ops::RangeInclusive, vec};
 
 use eframe::emath;
-use egui::{Painter, Rect, Pos2, Stroke, Color32, plot::{Plot, Line, PlotPoints}};
-use rand::{rngs::StdRng, SeedableRng};
+use egui::{Painter, Rect, Pos2, Stroke, Color32, plot::{Plot, Line, PlotPoints, Points, MarkerShape, Bar, BarChart, Polygon}};
+use rand::SeedableRng;
 
-use crate::physics::{Simulation, ActivationFunc, GermGenesis};
+use crate::{
+    analysis::{self, LoopMetrics, PundSummary, SwitchingPulse},
+    checkpoint::SimRng,
+    dipole::{Depolarization, Electrodes},
+    disorder::Distribution,
+    expression::Expression,
+    fatigue::{CycleRecord, Fatigue},
+    forc::{Forc, ForcMap, ForcRun},
+    maps::{CellMap, Channel, MapKind},
+    physics::{
+        ActivationFunc, Anisotropy, Boundary, FieldTend, Geometry, GermGenesis, NucleationLaw,
+        Simulation, Slice, Stencil, Variants, Waveform,
+    },
+    retention::{Relaxation, Retention, RetentionRun},
+};
+
+/// Steps kept in the histories of the P(E) and I(t) views, older halves of them are dropped;
+/// the P(T) history is thinned to half instead
+const HISTORY: usize = 200_000;
+/// Most field cycles shown on P(E), the history keeps no older ones
+const LOOP_CYCLES: usize = 20;
+
+/// Result of an analysis of the histories, computed again only when new steps are recorded or its settings change
+#[derive(Default)]
+struct Cached<T>{
+    key: Option<(u64, usize)>, // recorded steps and the setting
+    value: T
+}
+
+impl<T> Cached<T>{
+    fn get(&mut self, key: (u64, usize), compute: impl FnOnce() -> T) -> &T{
+        if self.key != Some(key){
+            self.value = compute();
+            self.key = Some(key);
+        }
+        &self.value
+    }
+}
+
+#[derive(PartialEq, Default, serde::Deserialize, serde::Serialize)]
+enum View{
+    /// Only active cells
+    #[default]
+    Front,
+    /// Polarization of every cell
+    Lattice
+}
+
+#[derive(PartialEq, Default, serde::Deserialize, serde::Serialize)]
+enum PlotMode{
+    #[default]
+    Time,
+    Loop,
+    Current,
+    Pund,
+    /// Polarization of each layer
+    Layers,
+    /// Polarization against temperature
+    Temperature,
+    /// Remanent polarization of every period
+    Cycles
+}
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -16,12 +77,70 @@ pub struct App {
     #[serde(skip)]
     points: Vec<(f64, f64)>,
     #[serde(skip)]
+    loop_points: Vec<(f64, f64)>, // (field, polarization) after every step
+    #[serde(skip)]
+    cycle_starts: Vec<usize>, // indices of `loop_points` where field cycles start, see `analysis::split_cycles`
+    #[serde(skip)]
+    pund_start: usize, // index of `loop_points` where the PUND waveform was chosen
+    #[serde(skip)]
+    recorded: u64, // steps recorded and clearings of the histories, keys the cached analyses
+    #[serde(skip)]
+    loop_metrics: Cached<(LoopMetrics, Vec<[f64; 2]>)>, // of the shown cycles, with coercive points
+    #[serde(skip)]
+    current_pulses: Cached<(Vec<f64>, Vec<SwitchingPulse>)>, // smoothed current and its pulses
+    #[serde(skip)]
+    current_points: Vec<f64>, // switching current at every step
+    #[serde(skip)]
+    reversals: Vec<(usize, bool)>, // step index of field reversal, whether it is upwards
+    #[serde(skip)]
+    temperature_points: Vec<(f64, f64)>, // (temperature, polarization) after every `temperature_stride` steps
+    #[serde(skip)]
+    temperature_stride: u64, // doubled when every other sample is dropped from a too long history
+    #[serde(skip)]
     time: f64,
     #[serde(skip)]
-    rng: StdRng,
+    rng: SimRng,
     #[serde(skip)]
     double_step: bool,
-    seed: i32 // seed that will be used after reset <0 => random
+    seed: i32, // seed that will be used after reset <0 => random
+    plot_mode: PlotMode,
+    loop_cycles: usize, // number of last cycles shown on P(E)
+    current_smoothing: usize, // half width of current moving average
+    points_text: String, // points of piecewise linear waveform
+    #[serde(skip)]
+    points_error: Option<String>,
+    forc: Forc,
+    forc_speed: u32, // steps per frame during FORC and retention measurements
+    #[serde(skip)]
+    forc_run: Option<ForcRun>,
+    #[serde(skip)]
+    forc_map: Option<ForcMap>,
+    retention: Retention,
+    #[serde(skip)]
+    retention_run: Option<RetentionRun>, // running or finished
+    view: View,
+    show_front: bool, // active cells over the lattice
+    #[serde(skip)]
+    lattice_texture: Option<egui::TextureHandle>,
+    checkpoint_path: String,
+    #[serde(skip)]
+    checkpoint_message: Option<String>,
+    offsets_text: String, // of custom stencil
+    #[serde(skip)]
+    offsets_error: Option<String>,
+    layer: usize, // shown layer of a thick lattice
+    projection: bool, // show all layers mixed instead of one
+    ramp_text: String, // points of temperature ramp
+    #[serde(skip)]
+    ramp_error: Option<String>,
+    activation_text: String, // formula of custom activation function
+    #[serde(skip)]
+    activation_error: Option<String>,
+    map_path: String, // PNG image to import
+    map_channel: Channel,
+    map_kind: MapKind,
+    #[serde(skip)]
+    map_message: Option<String>
 }
 
 impl Default for App {
@@ -30,11 +149,49 @@ impl Default for App {
         Self {
             time: 0.0,
             points: vec![],
+            loop_points: vec![],
+            cycle_starts: vec![],
+            pund_start: 0,
+            recorded: 0,
+            loop_metrics: Cached::default(),
+            current_pulses: Cached::default(),
+            current_points: vec![],
+            reversals: vec![],
+            temperature_points: vec![],
+            temperature_stride: 1,
             double_step: false,
             simulation:  Simulation::new(100, 100),
             paused: false,
-            rng: StdRng::from_entropy(),
-            seed: -1
+            rng: SimRng::from_entropy(),
+            seed: -1,
+            plot_mode: PlotMode::Time,
+            loop_cycles: 3,
+            current_smoothing: 0,
+            points_text: "0:0; 250:1; 500:0; 750:-1; 1000:0".to_owned(),
+            points_error: None,
+            forc: Forc::default(),
+            forc_speed: 20,
+            forc_run: None,
+            forc_map: None,
+            retention: Retention::default(),
+            retention_run: None,
+            view: View::Front,
+            show_front: true,
+            lattice_texture: None,
+            checkpoint_path: "checkpoint.json".to_owned(),
+            checkpoint_message: None,
+            offsets_text: "0,-1,0.5; -1,0,1; 1,0,1; 0,1,0.5; 2,0,0.3; -2,0,0.3".to_owned(),
+            offsets_error: None,
+            layer: 0,
+            projection: false,
+            ramp_text: String::new(),
+            ramp_error: None,
+            activation_text: "w^2".to_owned(),
+            activation_error: None,
+            map_path: "map.png".to_owned(),
+            map_channel: Channel::Luminance,
+            map_kind: MapKind::Polarization,
+            map_message: None
         }
     }
 }
@@ -57,16 +214,141 @@ impl App {
         }
     }
 
+    /// Restarts the simulation and clears the recorded data
     pub fn reset(&mut self){
         if self.seed >= 0{
-            self.rng = StdRng::seed_from_u64(self.seed as u64);
+            self.rng = SimRng::seed_from_u64(self.seed as u64);
+        }
+        if let Some(mut run) = self.forc_run.take(){
+            run.restore(&mut self.simulation);
+        }
+        if let Some(run) = &mut self.retention_run{
+            run.restore(&mut self.simulation);
         }
         
         self.simulation.reset(&mut self.rng);
         
+        self.clear_records();
+    }
+
+    /// Part of the lattice that is shown
+    fn slice(&self) -> Slice{
+        if self.projection{
+            Slice::Projection
+        }
+        else{
+            Slice::Layer(self.layer.min(self.simulation.cells.depth - 1))
+        }
+    }
+
+    fn clear_records(&mut self){
         self.points.clear();
+        self.loop_points.clear();
+        self.cycle_starts.clear();
+        self.pund_start = 0;
+        self.recorded += 1;
+        self.current_points.clear();
+        self.reversals.clear();
+        self.temperature_points.clear();
+        self.temperature_stride = 1;
         self.time = 0.0;
     }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_checkpoint(&self) -> Result<(), String>{
+        use std::io::Write;
+
+        let file = std::fs::File::create(&self.checkpoint_path).map_err(|e| e.to_string())?;
+        let mut w = std::io::BufWriter::new(file);
+        crate::checkpoint::save(&mut w, &self.simulation, &self.rng)?;
+        w.flush().map_err(|e| e.to_string())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_checkpoint(&mut self) -> Result<(), String>{
+        let file = std::fs::File::open(&self.checkpoint_path).map_err(|e| e.to_string())?;
+        let (simulation, rng) = crate::checkpoint::load(std::io::BufReader::new(file))?;
+        self.simulation = simulation;
+        self.rng = rng;
+        self.forc_run = None;
+        self.retention_run = None;
+        self.clear_records();
+        Ok(())
+    }
+
+    /// Imports the image at `map_path` and resets the simulation to apply it
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_map(&mut self) -> Result<(), String>{
+        let file = std::fs::File::open(&self.map_path).map_err(|e| e.to_string())?;
+        let map = CellMap::from_png(std::io::BufReader::new(file), self.map_channel)?;
+        self.simulation.cells.import_map(self.map_kind, map)?;
+        self.reset();
+        Ok(())
+    }
+
+    fn step(&mut self){
+        if let Some(mut run) = self.forc_run.take(){
+            if run.step(&mut self.simulation, &mut self.rng){
+                self.forc_map = Some(ForcMap::new(&run.curves));
+            }
+            else{
+                self.forc_run = Some(run);
+            }
+        }
+        else if let Some(run) = self.retention_run.as_mut().filter(|r| !r.finished()){
+            run.step(&mut self.simulation, &mut self.rng);
+        }
+        else{
+            self.simulation.step(&mut self.rng);
+        }
+        let field = self.simulation.get_field() as f64;
+        if self.loop_points.last().map_or(false, |&(e, _)| e < 0.0) && field >= 0.0{
+            self.cycle_starts.push(self.loop_points.len());
+        }
+        self.loop_points.push((field, self.simulation.get_polarization()));
+        match self.simulation.get_tend(){
+            FieldTend::ReverseUp => self.reversals.push((self.current_points.len(), true)),
+            FieldTend::ReverseDown => self.reversals.push((self.current_points.len(), false)),
+            FieldTend::Stable => {}
+        }
+        self.current_points.push(self.simulation.get_current());
+        if self.recorded % self.temperature_stride == 0{
+            self.temperature_points.push((self.simulation.get_temperature() as f64, self.simulation.get_polarization()));
+        }
+        self.recorded += 1;
+        self.trim_records();
+    }
+
+    /// Drops the steps before the cycles that can be shown, or the older half of too long a history;
+    /// thins too long a P(T) history
+    fn trim_records(&mut self){
+        let mut first = self.cycle_starts.len().checked_sub(LOOP_CYCLES).map_or(0, |k| self.cycle_starts[k]);
+        if self.loop_points.len() - first > HISTORY{
+            first = self.loop_points.len() - HISTORY/2;
+        }
+        if self.temperature_points.len() > HISTORY{
+            let mut i = 0;
+            self.temperature_points.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.temperature_stride *= 2;
+        }
+        if first == 0{
+            return;
+        }
+        self.loop_points.drain(..first);
+        self.pund_start = self.pund_start.saturating_sub(first);
+        self.current_points.drain(..first);
+        self.reversals.retain(|r| r.0 >= first);
+        for r in &mut self.reversals{
+            r.0 -= first;
+        }
+        self.cycle_starts.retain_mut(|i| {
+            *i = i.saturating_sub(first);
+            *i > 0
+        });
+    }
 }
 
 impl eframe::App for App {
@@ -102,29 +384,50 @@ impl eframe::App for App {
             ui.checkbox(&mut self.paused, "Приостановить");
             ui.checkbox(&mut self.double_step, "Двойной шаг");
 
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.view, View::Front, "Фронт");
+                ui.selectable_value(&mut self.view, View::Lattice, "Вся решётка");
+                if self.view == View::Lattice{
+                    ui.checkbox(&mut self.show_front, "Фронт поверх");
+                }
+            });
+            if self.simulation.cells.depth > 1{
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.projection, "Проекция");
+                    if !self.projection{
+                        ui.add(egui::Slider::new(&mut self.layer, 0..=self.simulation.cells.depth - 1).text("Слой"));
+                    }
+                });
+            }
+
             egui::ComboBox::from_label("Зародышеобразование")
                 .selected_text(match self.simulation.germs {
                     GermGenesis::StartRandom { .. } => "Случайные",
                     GermGenesis::StartFixed { .. } => "Фиксированные",
                     GermGenesis::ContinuousRandom { .. } => "Постепенные",
+                    GermGenesis::FieldDriven { .. } => "Зависящие от поля",
                 })
                 .show_ui(ui, |ui| {
                     /*ui.selectable_value(&mut simulation.germs, GermGenesis::StartRandom{number: 5}, "Случайные зародыши при каждом переключении");
                     ui.selectable_value(&mut simulation.germs, GermGenesis::new_fixed(&mut simulation.cells, &mut self.rng, 5), "Фиксированные дефекты");
                     ui.selectable_value(&mut simulation.germs, GermGenesis::ContinuousRandom { chance: 0.1 }, "Случайное постепенное образование");
                     */
-                    if ui.selectable_label(if let GermGenesis::StartRandom{..} = self.simulation.germs {true} else {false},
+                    if ui.selectable_label(matches!(self.simulation.germs, GermGenesis::StartRandom{..}),
                          "Случайные зародыши").clicked(){
                             self.simulation.germs = GermGenesis::StartRandom{number: 5};
                     }
-                    if ui.selectable_label(if let GermGenesis::StartFixed{..} = self.simulation.germs {true} else {false},
+                    if ui.selectable_label(matches!(self.simulation.germs, GermGenesis::StartFixed{..}),
                          "Фиксированные зародыши").clicked(){
                             self.simulation.germs = GermGenesis::new_fixed(&mut self.simulation.cells, &mut self.rng, 5);
                     }
-                    if ui.selectable_label(if let GermGenesis::ContinuousRandom{..} = self.simulation.germs {true} else {false},
+                    if ui.selectable_label(matches!(self.simulation.germs, GermGenesis::ContinuousRandom{..}),
                         "Постепенное зарождение").clicked(){
                             self.simulation.germs = GermGenesis::ContinuousRandom { chance: 0.2 };
                    }
+                    if ui.selectable_label(matches!(self.simulation.germs, GermGenesis::FieldDriven{..}),
+                        "Зарождение по закону от поля").clicked(){
+                            self.simulation.germs = GermGenesis::FieldDriven { rate: 1e-4, law: NucleationLaw::Merz { alpha: 1.0 }, decay: 0.0, since: 0 };
+                   }
                 }
             );
 
@@ -135,12 +438,142 @@ impl eframe::App for App {
                 GermGenesis::ContinuousRandom { chance } => {
                     ui.add(egui::Slider::new(chance, 0.0..=1.0).text("Шанс зародышеобразования на тик"));
                 },
+                GermGenesis::FieldDriven { rate, law, decay, .. } => {
+                    ui.add(egui::Slider::new(rate, 1e-7..=1e-2).logarithmic(true).text("Частота зарождения на ячейку"));
+                    egui::ComboBox::from_label("Закон от поля")
+                        .selected_text(match law {
+                            NucleationLaw::Merz { .. } => "Мерц",
+                            NucleationLaw::Power { .. } => "Степенной",
+                        })
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(matches!(law, NucleationLaw::Merz{..}), "Мерц exp(-α/|E|)").clicked(){
+                                *law = NucleationLaw::Merz { alpha: 1.0 };
+                            }
+                            if ui.selectable_label(matches!(law, NucleationLaw::Power{..}), "Степенной (|E|/E₀)ⁿ").clicked(){
+                                *law = NucleationLaw::Power { field: 1.0, exponent: 2.0 };
+                            }
+                        }
+                    );
+                    match law {
+                        NucleationLaw::Merz { alpha } => {
+                            ui.add(egui::Slider::new(alpha, 0.0..=10.0).text("α"));
+                        },
+                        NucleationLaw::Power { field, exponent } => {
+                            ui.add(egui::Slider::new(field, 0.01..=5.0).text("E₀"));
+                            ui.add(egui::Slider::new(exponent, 0.0..=10.0).text("n"));
+                        },
+                    }
+                    ui.add(egui::Slider::new(decay, 0.0..=100_000.0).logarithmic(true).text("Время затухания после переключения"));
+                },
             }
 
            // ui.add(egui::Slider::new(&mut simulation.germs, 1..=10).text("Число зародышей"));
             ui.add(egui::Slider::new(&mut self.simulation.cells.x_spread, 0.0..=2.0).text("Скорость по x"));
             ui.add(egui::Slider::new(&mut self.simulation.cells.y_spread, 0.0..=2.0).text("Скорость по y"));
+            if self.simulation.cells.depth > 1{
+                ui.add(egui::Slider::new(&mut self.simulation.cells.z_spread, 0.0..=2.0).text("Скорость по толщине"));
+            }
+            let mut anisotropic = self.simulation.cells.anisotropy.is_some();
+            if ui.checkbox(&mut anisotropic, "Направленная анизотропия").changed(){
+                self.simulation.cells.anisotropy = if anisotropic {Some(Anisotropy::default())} else {None};
+            }
+            if let Some(a) = &mut self.simulation.cells.anisotropy{
+                let names = ["Множитель к +x", "Множитель к −x", "Множитель к +y", "Множитель к −y"];
+                ui.label("При переключении вверх");
+                for (f, name) in a.up.iter_mut().zip(names){
+                    ui.add(egui::Slider::new(f, 0.0..=3.0).text(name));
+                }
+                let mut by_sign = a.down.is_some();
+                if ui.checkbox(&mut by_sign, "Иначе при переключении вниз").changed(){
+                    a.down = if by_sign {Some(a.up)} else {None};
+                }
+                if let Some(down) = &mut a.down{
+                    for (f, name) in down.iter_mut().zip(names){
+                        ui.add(egui::Slider::new(f, 0.0..=3.0).text(name));
+                    }
+                }
+            }
+
+            let cells = &mut self.simulation.cells;
+            egui::ComboBox::from_label("Решётка")
+                .selected_text(match cells.geometry {
+                    Geometry::Square => "Квадратная",
+                    Geometry::Hexagonal => "Гексагональная",
+                    Geometry::Triangular => "Треугольная",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut cells.geometry, Geometry::Square, "Квадратная");
+                    ui.selectable_value(&mut cells.geometry, Geometry::Hexagonal, "Гексагональная");
+                    ui.selectable_value(&mut cells.geometry, Geometry::Triangular, "Треугольная");
+                }
+            );
+
+            if cells.geometry != Geometry::Square{
+                for (spread, label) in cells.lattice_spread.iter_mut().zip(["Скорость по направлению 1", "Скорость по направлению 2", "Скорость по направлению 3"]){
+                    ui.add(egui::Slider::new(spread, 0.0..=2.0).text(label));
+                }
+            }
+            else{
+                egui::ComboBox::from_label("Соседи")
+                    .selected_text(match cells.stencil {
+                        Stencil::VonNeumann { .. } => "Фон Неймана",
+                        Stencil::Moore { .. } => "Мура",
+                        Stencil::Custom { .. } => "Свои",
+                    })
+                    .show_ui(ui, |ui| {
+                        if ui.selectable_label(matches!(cells.stencil, Stencil::VonNeumann{..}), "Фон Неймана").clicked(){
+                            cells.stencil = Stencil::VonNeumann { range: 1 };
+                        }
+                        if ui.selectable_label(matches!(cells.stencil, Stencil::Moore{..}), "Мура").clicked(){
+                            cells.stencil = Stencil::Moore { range: 1 };
+                        }
+                        if ui.selectable_label(matches!(cells.stencil, Stencil::Custom{..}), "Свои").clicked(){
+                            match parse_offsets(&self.offsets_text){
+                                Ok(offsets) => cells.stencil = Stencil::Custom { offsets },
+                                Err(e) => self.offsets_error = Some(e)
+                            }
+                        }
+                    }
+                );
+                match &mut cells.stencil{
+                    Stencil::VonNeumann { range } | Stencil::Moore { range } => {
+                        ui.add(egui::Slider::new(range, 1..=5).text("Радиус"));
+                        ui.add(egui::Slider::new(&mut cells.diagonal_spread, 0.0..=2.0).text("Скорость по диагонали"));
+                    },
+                    Stencil::Custom { offsets } => {
+                        ui.label("Соседи \"dx,dy,вес\" через ;");
+                        if ui.text_edit_singleline(&mut self.offsets_text).changed(){
+                            match parse_offsets(&self.offsets_text){
+                                Ok(o) => {*offsets = o; self.offsets_error = None},
+                                Err(e) => self.offsets_error = Some(e)
+                            }
+                        }
+                        if let Some(e) = &self.offsets_error{
+                            ui.colored_label(Color32::RED, e);
+                        }
+                    }
+                }
+            }
 
+            let cells = &mut self.simulation.cells;
+            for (boundary, label) in [(&mut cells.boundary_x, "Граница по x"), (&mut cells.boundary_y, "Граница по y"), (&mut cells.boundary_z, "Граница по z")]{
+                let name = |b: &Boundary| match b {
+                    Boundary::Open => "Открытая",
+                    Boundary::Periodic => "Периодическая",
+                    Boundary::Reflecting => "Отражающая",
+                };
+                egui::ComboBox::from_label(label)
+                    .selected_text(name(boundary))
+                    .show_ui(ui, |ui| {
+                        for b in [Boundary::Open, Boundary::Periodic, Boundary::Reflecting]{
+                            ui.selectable_value(boundary, b, name(&b));
+                        }
+                    }
+                );
+            }
+
+            let (field, temperature) = (self.simulation.gen.amplitude, self.simulation.get_temperature());
+            let barrier = self.simulation.thermostat.barrier(temperature);
             let act_func = &mut self.simulation.cells.activation_func;
             egui::ComboBox::from_label("Функция активации")
                 .selected_text(match act_func {
@@ -150,6 +583,7 @@ impl eframe::App for App {
                     ActivationFunc::SquareRoot => "SquareRoot",
                     ActivationFunc::Treshold => "Treshold",
                     ActivationFunc::Switch => "Switch",
+                    ActivationFunc::Custom { .. } => "Своя",
                 })
                 .show_ui(ui, |ui| {
                     ui.selectable_value(act_func, ActivationFunc::Linear, "Linear");
@@ -158,14 +592,173 @@ impl eframe::App for App {
                     ui.selectable_value(act_func, ActivationFunc::SquareRoot, "SquareRoot");
                     ui.selectable_value(act_func, ActivationFunc::Treshold, "Treshold");
                     ui.selectable_value(act_func, ActivationFunc::Switch, "Switch");
+                    if ui.selectable_label(matches!(act_func, ActivationFunc::Custom{..}), "Своя").clicked(){
+                        match Expression::parse(&self.activation_text){
+                            Ok(expression) => *act_func = ActivationFunc::Custom { expression },
+                            Err(e) => self.activation_error = Some(e)
+                        }
+                    }
                 }
             );
+            if let ActivationFunc::Custom { expression } = act_func{
+                ui.label("f(w, E, T), напр. w^2*exp(-1/T)");
+                if ui.text_edit_singleline(&mut self.activation_text).changed(){
+                    match Expression::parse(&self.activation_text){
+                        Ok(e) => {*expression = e; self.activation_error = None},
+                        Err(e) => self.activation_error = Some(e)
+                    }
+                }
+            }
+            if let Some(e) = &self.activation_error{
+                ui.colored_label(Color32::RED, e);
+            }
+            // switching probability per tick of a cell with weight w at the amplitude and the current temperature
+            Plot::new("activation").height(120.0).include_y(0.0).include_y(1.0).include_x(0.0).show(ui, |plot_ui| plot_ui.line(Line::new(
+                (0..=100).map(|i| {
+                    let w = i as f32*0.02;
+                    [w as f64, (-barrier/field/act_func.func(w, field, temperature)).exp() as f64]
+                }).collect::<PlotPoints>()).name("Вероятность переключения")));
+
+            let variants = &mut self.simulation.cells.variants;
+            let mut variants_changed = false;
+            egui::ComboBox::from_label("Варианты поляризации")
+                .selected_text(match variants {
+                    Variants::Uniaxial => "180°",
+                    Variants::Tetragonal { .. } => "Тетрагональные",
+                    Variants::Rhombohedral { .. } => "Ромбоэдрические",
+                })
+                .show_ui(ui, |ui| {
+                    for (v, name) in [
+                        (Variants::Uniaxial, "180°"),
+                        (Variants::Tetragonal { rate_180: 0.5, rate_90: 0.3 }, "Тетрагональные"),
+                        (Variants::Rhombohedral { rate_71: 0.5, rate_109: 0.3, rate_180: 0.2 }, "Ромбоэдрические")
+                    ]{
+                        if ui.selectable_label(std::mem::discriminant(variants) == std::mem::discriminant(&v), name).clicked(){
+                            *variants = v;
+                            variants_changed = true;
+                        }
+                    }
+                }
+            );
+            match variants{
+                Variants::Uniaxial => {},
+                Variants::Tetragonal { rate_180, rate_90 } => {
+                    ui.add(egui::Slider::new(rate_180, 0.0..=2.0).text("Скорость 180°"));
+                    ui.add(egui::Slider::new(rate_90, 0.0..=2.0).text("Скорость 90°"));
+                },
+                Variants::Rhombohedral { rate_71, rate_109, rate_180 } => {
+                    ui.add(egui::Slider::new(rate_71, 0.0..=2.0).text("Скорость 71°"));
+                    ui.add(egui::Slider::new(rate_109, 0.0..=2.0).text("Скорость 109°"));
+                    ui.add(egui::Slider::new(rate_180, 0.0..=2.0).text("Скорость 180°"));
+                },
+            }
+            ui.horizontal_wrapped(|ui| {
+                for v in 0..variants.count() as u8{
+                    ui.colored_label(Simulation::VARIANT_COLORS[v as usize], variants.label(v));
+                }
+            });
+            if variants_changed{
+                self.reset();
+            }
+
+            let cells = &mut self.simulation.cells;
+            let mut long_range = cells.depolarization.is_some();
+            if ui.checkbox(&mut long_range, "Поле деполяризации").changed(){
+                cells.depolarization = if long_range {Some(Depolarization::default())} else {None};
+            }
+            if let Some(d) = &mut cells.depolarization{
+                ui.add(egui::Slider::new(&mut d.strength, 0.0..=1.0).logarithmic(true).text("Сила диполя"));
+                ui.add(egui::Slider::new(&mut d.screening, 0.0..=50.0).text("Длина экранирования (0 — нет)"));
+                egui::ComboBox::from_label("Электроды")
+                    .selected_text(match d.electrodes {
+                        Electrodes::Shorted => "Закороченные",
+                        Electrodes::Open => "Разомкнутые",
+                        Electrodes::Partial { .. } => "Частичное экранирование",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut d.electrodes, Electrodes::Shorted, "Закороченные");
+                        ui.selectable_value(&mut d.electrodes, Electrodes::Open, "Разомкнутые");
+                        if ui.selectable_label(matches!(d.electrodes, Electrodes::Partial{..}), "Частичное экранирование").clicked(){
+                            d.electrodes = Electrodes::Partial { compensation: 0.9 };
+                        }
+                    }
+                );
+                if let Electrodes::Partial { compensation } = &mut d.electrodes{
+                    ui.add(egui::Slider::new(compensation, 0.0..=1.0).text("Доля экранирования"));
+                }
+            }
 
 
             ui.label("Сигнал");
-            ui.add(egui::Slider::new(&mut self.simulation.gen.time_up, 1..=500_000).logarithmic(true).text("Время поля \"вверх\""));
-            ui.add(egui::Slider::new(&mut self.simulation.gen.time_down, 1..=500_000).logarithmic(true).text("Время поля \"вниз\""));
-            ui.add(egui::Slider::new(&mut self.simulation.gen.amplitude, 0.001..=5.0).text("Амплитуда поля"));
+            let gen = &mut self.simulation.gen;
+            egui::ComboBox::from_label("Форма сигнала")
+                .selected_text(match gen.shape {
+                    Waveform::Square => "Прямоугольный",
+                    Waveform::Sine => "Синус",
+                    Waveform::Triangle => "Треугольный",
+                    Waveform::Trapezoid { .. } => "Трапеция",
+                    Waveform::PiecewiseLinear { .. } => "Кусочно-линейный",
+                    Waveform::Pund { .. } => "PUND",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut gen.shape, Waveform::Square, "Прямоугольный");
+                    ui.selectable_value(&mut gen.shape, Waveform::Sine, "Синус");
+                    ui.selectable_value(&mut gen.shape, Waveform::Triangle, "Треугольный");
+                    if ui.selectable_label(matches!(gen.shape, Waveform::Trapezoid{..}), "Трапеция").clicked(){
+                        gen.shape = Waveform::Trapezoid { rise: 50, fall: 50 };
+                    }
+                    if ui.selectable_label(matches!(gen.shape, Waveform::PiecewiseLinear{..}), "Кусочно-линейный").clicked(){
+                        match parse_points(&self.points_text){
+                            Ok(points) => gen.shape = Waveform::PiecewiseLinear { points },
+                            Err(e) => self.points_error = Some(e)
+                        }
+                    }
+                    if ui.selectable_label(matches!(gen.shape, Waveform::Pund{..}), "PUND").clicked(){
+                        // the sequence starts with its first pulse, responses to earlier fields are not counted
+                        gen.shape = Waveform::Pund { width: 200, delay: 100 };
+                        gen.reset();
+                        self.pund_start = self.loop_points.len();
+                    }
+                }
+            );
+
+            if let Waveform::PiecewiseLinear { points } = &mut gen.shape{
+                ui.label("Точки \"время:значение\" через ;");
+                if ui.text_edit_singleline(&mut self.points_text).changed(){
+                    match parse_points(&self.points_text){
+                        Ok(p) => {*points = p; self.points_error = None},
+                        Err(e) => self.points_error = Some(e)
+                    }
+                }
+                if let Some(e) = &self.points_error{
+                    ui.colored_label(Color32::RED, e);
+                }
+            }
+            else if let Waveform::Pund { width, delay } = &mut gen.shape{
+                ui.add(egui::Slider::new(width, 1..=500_000).logarithmic(true).text("Длительность импульса"));
+                ui.add(egui::Slider::new(delay, 1..=500_000).logarithmic(true).text("Задержка"));
+            }
+            else{
+                ui.add(egui::Slider::new(&mut gen.time_up, 1..=500_000).logarithmic(true).text("Время поля \"вверх\""));
+                ui.add(egui::Slider::new(&mut gen.time_down, 1..=500_000).logarithmic(true).text("Время поля \"вниз\""));
+                ui.add(egui::Slider::new(&mut gen.hold, 0..=500_000).logarithmic(true).text("Выдержка после полуволны"));
+                if gen.hold > 0{
+                    ui.add(egui::Slider::new(&mut gen.hold_field, -0.5..=0.5).text("Поле выдержки"));
+                }
+            }
+            if let Waveform::Trapezoid { rise, fall } = &mut gen.shape{
+                ui.add(egui::Slider::new(rise, 0..=500_000).logarithmic(true).text("Время нарастания"));
+                ui.add(egui::Slider::new(fall, 0..=500_000).logarithmic(true).text("Время спада"));
+            }
+            ui.add(egui::Slider::new(&mut gen.amplitude, 0.001..=5.0).text("Амплитуда поля"));
+            ui.add(egui::Slider::new(&mut gen.bias, -5.0..=5.0).text("Постоянное смещение"));
+
+            let imprint = &mut self.simulation.imprint;
+            ui.label(format!("Встроенное поле сейчас: {:.4}", imprint.value()));
+            ui.add(egui::Slider::new(&mut imprint.field, -2.0..=2.0).text("Встроенное поле (импринт)"));
+            ui.add(egui::Slider::new(&mut imprint.per_cycle, -0.01..=0.01).text("Дрейф за период"));
+            ui.add(egui::Slider::new(&mut imprint.per_tick, -1e-4..=1e-4).text("Дрейф за тик вдоль P"));
+            ui.add(egui::Slider::new(&mut imprint.limit, 0.0..=2.0).text("Предел дрейфа (0 — нет)"));
 
             ui.add(egui::Separator::default());
 
@@ -175,6 +768,9 @@ impl eframe::App for App {
             if ui.add(egui::Slider::new(&mut self.simulation.cells.height, 1..=500).text("Высота")).changed(){
                 self.reset();
             };
+            if ui.add(egui::Slider::new(&mut self.simulation.cells.depth, 1..=50).text("Толщина")).changed(){
+                self.reset();
+            };
 
             ui.add(egui::Separator::default());
 
@@ -189,6 +785,32 @@ impl eframe::App for App {
             if ui.button("Сбросить").clicked() {
                 self.reset();
             }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                ui.add(egui::Separator::default());
+                ui.horizontal(|ui| {
+                    ui.label("Контрольная точка");
+                    ui.text_edit_singleline(&mut self.checkpoint_path);
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Сохранить").clicked(){
+                        self.checkpoint_message = Some(match self.save_checkpoint(){
+                            Ok(()) => "Сохранено".to_owned(),
+                            Err(e) => e
+                        });
+                    }
+                    if ui.button("Загрузить").clicked(){
+                        self.checkpoint_message = Some(match self.load_checkpoint(){
+                            Ok(()) => "Загружено".to_owned(),
+                            Err(e) => e
+                        });
+                    }
+                });
+                if let Some(m) = &self.checkpoint_message{
+                    ui.label(m);
+                }
+            }
             
         });
 
@@ -196,11 +818,16 @@ impl eframe::App for App {
             // The central panel the region left after adding TopPanel's and SidePanel's
             if !self.paused{
                 self.time += 0.01;
-                self.simulation.step(&mut self.rng);
+                self.step();
                 if self.double_step{
-                    self.simulation.step(&mut self.rng)
+                    self.step();
+                }
+                if self.forc_run.is_some() || self.retention_run.as_ref().map_or(false, |r| !r.finished()){
+                    for _ in 1..self.forc_speed{
+                        self.step();
+                    }
                 }
-                let /*mut*/ measure: f64 = self.simulation.get_polarization() as f64;
+                let /*mut*/ measure: f64 = self.simulation.get_polarization();
                 ui.ctx().request_repaint();
 
                 if self.time % 0.05 < 0.01{
@@ -227,7 +854,16 @@ impl eframe::App for App {
                 rect,
             );
             // simulation.set_transform(to_screen);
-            self.simulation.paint(&painter, to_screen);
+            let slice = self.slice();
+            match self.view{
+                View::Front => self.simulation.paint(&painter, to_screen, slice),
+                View::Lattice => {
+                    self.simulation.paint_lattice(&painter, to_screen, slice, &mut self.lattice_texture);
+                    if self.show_front{
+                        self.simulation.paint(&painter, to_screen, slice);
+                    }
+                }
+            }
             painter.rect_stroke(rect, 1.0, Stroke::new(1.0, Color32::from_gray(16)));
             // Make sure we allocate what we used (everything)
             ui.expand_to_include_rect(painter.clip_rect());
@@ -237,10 +873,440 @@ impl eframe::App for App {
 
         if true {
             egui::Window::new("Поляризация").show(ctx, |ui| {
-                Plot::new("data").include_y(0.0).include_x(0.0).auto_bounds_y().auto_bounds_x().show(ui, |plot_ui| plot_ui.line(Line::new(
-                    self.points.iter().map(|&(x, p)| {
-                        [x, p]}).collect::<PlotPoints>())));
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Time, "P(t)");
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Loop, "P(E)");
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Current, "I(t)");
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Pund, "PUND");
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Layers, "P(z)");
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Temperature, "P(T)");
+                    ui.selectable_value(&mut self.plot_mode, PlotMode::Cycles, "P_r(N)");
+                });
+
+                match self.plot_mode{
+                    PlotMode::Time => {
+                        Plot::new("data").include_y(0.0).include_x(0.0).auto_bounds_y().auto_bounds_x().show(ui, |plot_ui| plot_ui.line(Line::new(
+                            self.points.iter().map(|&(x, p)| {
+                                [x, p]}).collect::<PlotPoints>())));
+                    },
+                    PlotMode::Loop => {
+                        ui.add(egui::Slider::new(&mut self.loop_cycles, 1..=LOOP_CYCLES).text("Число циклов"));
+
+                        let first = self.cycle_starts.len().checked_sub(self.loop_cycles).map_or(0, |k| self.cycle_starts[k]);
+                        let cycles = analysis::split_cycles(&self.loop_points[first..]);
+                        let (metrics, coercive) = self.loop_metrics.get((self.recorded, self.loop_cycles), || {
+                            let mut metrics = LoopMetrics::default();
+                            let mut coercive = vec![];
+                            for c in &cycles{
+                                let m = LoopMetrics::new(c);
+                                coercive.extend(m.coercive_up.iter().chain(m.coercive_down.iter()).map(|&e| [e, m.level]));
+                                metrics.extend(m);
+                            }
+                            (metrics, coercive)
+                        });
+
+                        let format = |v: &[f64]| analysis::mean(v).map_or("—".to_owned(), |m| format!("{:.4}", m));
+                        ui.label(format!("E_c+ = {}, E_c− = {}", format(&metrics.coercive_up), format(&metrics.coercive_down)));
+                        ui.label(format!("P_r+ = {}, P_r− = {}", format(&metrics.remanent_up), format(&metrics.remanent_down)));
+                        ui.label(format!("Импринт (E_c+ + E_c−)/2 = {}", metrics.imprint().map_or("—".to_owned(), |m| format!("{:.4}", m))));
+
+                        Plot::new("loop").include_y(0.0).include_x(0.0).auto_bounds_y().auto_bounds_x().show(ui, |plot_ui| {
+                            for (i, c) in cycles.iter().enumerate(){
+                                plot_ui.line(Line::new(c.iter().map(|&(e, p)| [e, p]).collect::<PlotPoints>())
+                                    .name(format!("Цикл {}", i + 1)));
+                            }
+                            plot_ui.points(Points::new(coercive.iter().copied().collect::<PlotPoints>())
+                                .shape(MarkerShape::Diamond).radius(4.0).color(Color32::RED).name("E_c"));
+                            plot_ui.points(Points::new(metrics.remanent_up.iter().chain(metrics.remanent_down.iter())
+                                .map(|&p| [0.0, p]).collect::<PlotPoints>())
+                                .shape(MarkerShape::Circle).radius(4.0).color(Color32::LIGHT_BLUE).name("P_r"));
+                        });
+                    }
+                    PlotMode::Current => {
+                        ui.add(egui::Slider::new(&mut self.current_smoothing, 0..=50).text("Сглаживание"));
+
+                        let (current, pulses) = self.current_pulses.get((self.recorded, self.current_smoothing), || {
+                            let current = analysis::smooth(&self.current_points, self.current_smoothing);
+                            let pulses = analysis::switching_pulses(&current, &self.reversals);
+                            (current, pulses)
+                        });
+
+                        for p in pulses.iter().rev().take(4).rev(){
+                            ui.label(format!("{} при {}: I_max = {:.5}, t_max = {}, t_s = {}",
+                                if p.up {"↑"} else {"↓"}, p.start, p.peak_current, p.peak_time,
+                                p.switching_time.map_or("—".to_owned(), |t| t.to_string())));
+                        }
+
+                        Plot::new("current").include_y(0.0).include_x(0.0).auto_bounds_y().auto_bounds_x().show(ui, |plot_ui| {
+                            plot_ui.line(Line::new(current.iter().enumerate().map(|(i, &c)| [i as f64, c]).collect::<PlotPoints>()));
+                            plot_ui.points(Points::new(pulses.iter().map(|p| [(p.start + p.peak_time) as f64, p.peak_current]).collect::<PlotPoints>())
+                                .shape(MarkerShape::Diamond).radius(4.0).color(Color32::RED).name("I_max"));
+                        });
+                    }
+                    PlotMode::Pund => {
+                        let responses = analysis::pund_responses(&self.loop_points[self.pund_start..], self.simulation.gen.bias as f64);
+                        let summary = PundSummary::new(&responses);
+
+                        let format = |v: Option<f64>| v.map_or("—".to_owned(), |m| format!("{:.4}", m));
+                        let names = ["P", "U", "N", "D"];
+                        for (i, name) in names.iter().enumerate(){
+                            ui.label(format!("{}: ΔP = {}, ΔP после задержки = {}", name, format(summary.during[i]), format(summary.remanent[i])));
+                        }
+                        let (switched_up, switched_down) = summary.switched();
+                        ui.label(format!("P − U = {}, N − D = {}", format(switched_up), format(switched_down)));
+
+                        Plot::new("pund").include_y(0.0).show(ui, |plot_ui| {
+                            plot_ui.bar_chart(BarChart::new(names.iter().enumerate()
+                                .map(|(i, name)| Bar::new(i as f64, summary.during[i].unwrap_or(0.0)).name(name)).collect())
+                                .width(0.4).name("ΔP"));
+                            plot_ui.bar_chart(BarChart::new(names.iter().enumerate()
+                                .map(|(i, name)| Bar::new(i as f64 + 0.4, summary.remanent[i].unwrap_or(0.0)).name(name)).collect())
+                                .width(0.4).name("ΔP после задержки"));
+                        });
+                    }
+                    PlotMode::Layers => {
+                        let cells = &self.simulation.cells;
+                        Plot::new("layers").include_y(0.0).include_y(1.0).show(ui, |plot_ui| {
+                            plot_ui.bar_chart(BarChart::new((0..cells.depth)
+                                .map(|z| Bar::new(z as f64, cells.layer_polarization(z)).name(format!("Слой {}", z))).collect())
+                                .name("Доля ↑"));
+                        });
+                    }
+                    PlotMode::Temperature => {
+                        Plot::new("temperature").include_y(0.0).include_x(0.0).auto_bounds_y().auto_bounds_x().show(ui, |plot_ui| plot_ui.line(Line::new(
+                            self.temperature_points.iter().map(|&(t, p)| [t, p]).collect::<PlotPoints>())));
+                    }
+                    PlotMode::Cycles => {
+                        let cycles = self.simulation.get_cycles();
+                        let line = |f: fn(&CycleRecord) -> Option<f64>| Line::new(cycles.iter().enumerate()
+                            .filter_map(|(i, c)| f(c).map(|v| [(i + 1) as f64, v])).collect::<PlotPoints>());
+                        Plot::new("cycles").include_y(0.0).include_y(1.0).include_x(0.0).legend(Default::default()).show(ui, |plot_ui| {
+                            plot_ui.line(line(|c| c.remanent_up).name("P_r+"));
+                            plot_ui.line(line(|c| c.remanent_down).name("P_r−"));
+                            plot_ui.line(line(|c| Some(c.pinned)).name("Доля закреплённых"));
+                        });
+                    }
+                }
             });
         }
+
+        egui::Window::new("Температура").default_open(false).show(ctx, |ui| {
+            ui.label(format!("T = {:.3}", self.simulation.get_temperature()));
+            let thermostat = &mut self.simulation.thermostat;
+            ui.add(egui::Slider::new(&mut thermostat.temperature, 0.05..=5.0).logarithmic(true).text("Температура"));
+
+            let mut has_curie = thermostat.curie.is_some();
+            if ui.checkbox(&mut has_curie, "Температура Кюри").changed(){
+                thermostat.curie = if has_curie {Some(2.0)} else {None};
+            }
+            if let Some(curie) = &mut thermostat.curie{
+                ui.add(egui::Slider::new(curie, 0.05..=10.0).logarithmic(true).text("T_c"));
+            }
+
+            ui.label("Нагрев \"время:температура\" через ;, пусто — без нагрева");
+            if ui.text_edit_singleline(&mut self.ramp_text).changed(){
+                if self.ramp_text.trim().is_empty(){
+                    thermostat.ramp.clear();
+                    self.ramp_error = None;
+                }
+                else{
+                    match parse_points(&self.ramp_text){
+                        Ok(points) => {thermostat.ramp = points; self.ramp_error = None},
+                        Err(e) => self.ramp_error = Some(e)
+                    }
+                }
+            }
+            if let Some(e) = &self.ramp_error{
+                ui.colored_label(Color32::RED, e);
+            }
+        });
+
+        egui::Window::new("Беспорядок").default_open(false).show(ctx, |ui| {
+            let disorder = &mut self.simulation.cells.disorder;
+            distribution_ui(ui, "Сдвиг поля", &mut disorder.field, -2.0..=2.0);
+            distribution_ui(ui, "Барьер", &mut disorder.barrier, 0.0..=4.0);
+            distribution_ui(ui, "Связи", &mut disorder.bond, 0.0..=4.0);
+            ui.label("Значения разыгрываются при сбросе");
+            if ui.button("Сбросить").clicked(){
+                self.reset();
+            }
+        });
+
+        egui::Window::new("Усталость").default_open(false).show(ctx, |ui| {
+            let cells = &mut self.simulation.cells;
+            let mut enabled = cells.fatigue.is_some();
+            if ui.checkbox(&mut enabled, "Закрепление при переключениях").changed(){
+                cells.fatigue = if enabled {Some(Fatigue::default())} else {None};
+            }
+            if let Some(f) = &mut cells.fatigue{
+                ui.add(egui::Slider::new(&mut f.pin, 0.0..=0.1).logarithmic(true).text("Вероятность закрепления"));
+                ui.add(egui::Slider::new(&mut f.unpin, 0.0..=1.0).logarithmic(true).text("Вероятность освобождения"));
+                ui.add(egui::Slider::new(&mut f.strength, 1.0..=20.0).logarithmic(true).text("Множитель барьера"));
+                ui.add(egui::Slider::new(&mut f.initial, 0.0..=1.0).text("Закреплено при сбросе"));
+            }
+            ui.label(format!("Закреплено: {:.4}, периодов: {}", cells.pinned_share(), self.simulation.get_cycles().len()));
+            ui.label("Начальное закрепление разыгрывается при сбросе");
+            if ui.button("Сбросить").clicked(){
+                self.reset();
+            }
+        });
+
+        egui::Window::new("Зёрна").default_open(false).show(ctx, |ui| {
+            let grains = &mut self.simulation.cells.grains;
+            ui.add(egui::Slider::new(&mut grains.count, 0..=500).text("Число зёрен (0 — монокристалл)"));
+            distribution_ui(ui, "Наклон оси, °", &mut grains.tilt, 0.0..=90.0);
+            distribution_ui(ui, "Множитель скоростей", &mut grains.spread, 0.0..=4.0);
+            distribution_ui(ui, "Плотность зародышей", &mut grains.nucleation, 0.0..=4.0);
+            ui.add(egui::Slider::new(&mut grains.transmission, 0.0..=1.0).text("Проницаемость границ"));
+            ui.label("Зёрна строятся при сбросе");
+            if ui.button("Сбросить").clicked(){
+                self.reset();
+            }
+        });
+
+        egui::Window::new("Карты").default_open(false).show(ctx, |ui| {
+            let kinds = [(MapKind::Polarization, "Поляризация"), (MapKind::Germs, "Зародыши"), (MapKind::Pinning, "Пиннинг")];
+
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                ui.horizontal(|ui| {
+                    ui.label("PNG");
+                    ui.text_edit_singleline(&mut self.map_path);
+                });
+                egui::ComboBox::from_label("Канал")
+                    .selected_text(format!("{:?}", self.map_channel))
+                    .show_ui(ui, |ui| {
+                        for c in [Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha, Channel::Luminance]{
+                            ui.selectable_value(&mut self.map_channel, c, format!("{:?}", c));
+                        }
+                    }
+                );
+                egui::ComboBox::from_label("Что задаёт")
+                    .selected_text(kinds.iter().find(|k| k.0 == self.map_kind).unwrap().1)
+                    .show_ui(ui, |ui| {
+                        for (kind, name) in kinds{
+                            ui.selectable_value(&mut self.map_kind, kind, name);
+                        }
+                    }
+                );
+                if ui.button("Загрузить").clicked(){
+                    self.map_message = Some(match self.load_map(){
+                        Ok(()) => "Загружено".to_owned(),
+                        Err(e) => e
+                    });
+                }
+                if let Some(m) = &self.map_message{
+                    ui.label(m);
+                }
+                ui.add(egui::Separator::default());
+            }
+
+            let mut removed = false;
+            for (kind, name) in kinds{
+                let map = self.simulation.cells.maps.get_mut(kind);
+                if let Some((width, height)) = map.as_ref().map(|m| (m.width, m.height)){
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}: {}×{}", name, width, height));
+                        if ui.button("Убрать").clicked(){
+                            *map = None;
+                            removed = true;
+                        }
+                    });
+                }
+            }
+            ui.add(egui::Slider::new(&mut self.simulation.cells.maps.pinning_scale, 0.0..=20.0).text("Сила пиннинга"));
+            if self.simulation.cells.maps.germs.is_some(){
+                ui.label("Зародыши: светлые точки карты — вверх, серые — вниз");
+            }
+            if self.simulation.cells.maps.germs.is_some() && !matches!(self.simulation.germs, GermGenesis::StartFixed { .. }){
+                ui.label("Зародыши с карты используются при фиксированных зародышах");
+            }
+            if removed{
+                self.reset();
+            }
+        });
+
+        egui::Window::new("Удержание").default_open(false).show(ctx, |ui| {
+            let cells = &mut self.simulation.cells;
+            let mut relax = cells.relaxation.is_some();
+            if ui.checkbox(&mut relax, "Обратное переключение при слабом поле").changed(){
+                cells.relaxation = if relax {Some(Relaxation::default())} else {None};
+            }
+            if let Some(r) = &mut cells.relaxation{
+                ui.add(egui::Slider::new(&mut r.below, 0.0..=0.5).text("Поле, считающееся слабым"));
+                ui.add(egui::Slider::new(&mut r.nucleation, 0.0..=2.0).text("Вес без соседей"));
+            }
+
+            ui.label("Измерение удержания");
+            ui.add(egui::Slider::new(&mut self.retention.field, -5.0..=5.0).text("Поле записи"));
+            ui.add(egui::Slider::new(&mut self.retention.write, 1..=100_000).logarithmic(true).text("Длительность записи"));
+            ui.add(egui::Slider::new(&mut self.retention.hold_field, -0.5..=0.5).text("Поле выдержки"));
+            ui.add(egui::Slider::new(&mut self.retention.hold, 10..=10_000_000).logarithmic(true).text("Длительность выдержки"));
+            ui.add(egui::Slider::new(&mut self.retention.per_decade, 1..=50).text("Точек на декаду"));
+            ui.add(egui::Slider::new(&mut self.forc_speed, 1..=1000).logarithmic(true).text("Шагов за кадр"));
+
+            match &mut self.retention_run{
+                Some(run) if !run.finished() => {
+                    ui.label(format!("Выдержка: {} точек", run.points.len()));
+                    if ui.button("Остановить").clicked(){
+                        run.restore(&mut self.simulation);
+                    }
+                }
+                _ => if ui.button("Измерить").clicked(){
+                    self.retention_run = Some(RetentionRun::new(self.retention.clone()));
+                }
+            }
+
+            if let Some(run) = &self.retention_run{
+                Plot::new("retention").include_y(0.0).include_y(1.0).include_x(0.0).show(ui, |plot_ui| plot_ui.line(Line::new(
+                    run.retained().into_iter().map(|(t, p)| [t, p]).collect::<PlotPoints>()).name("Доля записанной поляризации от lg t")));
+            }
+        });
+
+        egui::Window::new("FORC").default_open(false).show(ctx, |ui| {
+            ui.add(egui::Slider::new(&mut self.forc.saturation, 0.001..=5.0).text("Поле насыщения"));
+            ui.add(egui::Slider::new(&mut self.forc.curves, 2..=200).text("Число кривых"));
+            ui.add(egui::Slider::new(&mut self.forc.hold, 0..=100_000).logarithmic(true).text("Выдержка в насыщении"));
+            ui.add(egui::Slider::new(&mut self.forc.sweep, 2..=500_000).logarithmic(true).text("Время развёртки"));
+            ui.add(egui::Slider::new(&mut self.forc_speed, 1..=1000).logarithmic(true).text("Шагов за кадр"));
+
+            if let Some(run) = &mut self.forc_run{
+                ui.label(format!("Кривая {} из {}", run.curves.len(), run.protocol.curves));
+                if ui.button("Остановить").clicked(){
+                    run.restore(&mut self.simulation);
+                    self.forc_map = Some(ForcMap::new(&run.curves));
+                    self.forc_run = None;
+                }
+            }
+            else if ui.button("Измерить").clicked(){
+                self.forc_run = Some(ForcRun::new(self.forc.clone()));
+                self.forc_map = None;
+            }
+
+            if let Some(map) = &self.forc_map{
+                if ui.button("Копировать CSV").clicked(){
+                    let mut csv = vec![];
+                    map.write_csv(&mut csv).unwrap();
+                    ui.output_mut(|o| o.copied_text = String::from_utf8(csv).unwrap());
+                }
+
+                let scale = map.max_abs();
+                Plot::new("forc").data_aspect(1.0).show(ui, |plot_ui| {
+                    let f = &map.fields;
+                    for (j, row) in map.rho.iter().enumerate(){
+                        for (i, &r) in row.iter().enumerate(){
+                            if !r.is_finite(){
+                                continue;
+                            }
+                            let (e1, e2, r1, r2) = (f[i], f[i + 1], f[j], f[j + 1]);
+                            plot_ui.polygon(Polygon::new(PlotPoints::new(vec![[e1, r1], [e2, r1], [e2, r2], [e1, r2]]))
+                                .color(diverging(r / scale)).fill_alpha(1.0).width(0.0));
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Blue for -1, white for 0, red for 1
+fn diverging(v: f64) -> Color32{
+    let v = v.clamp(-1.0, 1.0);
+    let fade = (255.0 * (1.0 - v.abs())) as u8;
+    if v > 0.0 {Color32::from_rgb(255, fade, fade)} else {Color32::from_rgb(fade, fade, 255)}
+}
+
+/// Parses "t:v; t:v" into points of a piecewise linear waveform
+fn parse_points(text: &str) -> Result<Vec<(u32, f32)>, String>{
+    let mut points = vec![];
+    for p in text.split(';').map(str::trim).filter(|p| !p.is_empty()){
+        let (t, v) = p.split_once(':').ok_or_else(|| format!("нет \":\" в \"{}\"", p))?;
+        let t: u32 = t.trim().parse().map_err(|_| format!("неверное время \"{}\"", t.trim()))?;
+        let v: f32 = v.trim().parse().map_err(|_| format!("неверное значение \"{}\"", v.trim()))?;
+        if points.last().map_or(false, |&(last, _)| t <= last){
+            return Err(format!("время {} не возрастает", t));
+        }
+        points.push((t, v));
+    }
+    if points.is_empty(){
+        return Err("нет точек".to_owned());
+    }
+    Ok(points)
+}
+
+/// Choice of a distribution and its parameters, values lie in `range`
+fn distribution_ui(ui: &mut egui::Ui, label: &str, d: &mut Distribution, range: RangeInclusive<f32>){
+    let span = range.end() - range.start();
+    let center = match *d{
+        Distribution::Constant(v) => v,
+        Distribution::Uniform { low, high } => (low + high)/2.0,
+        Distribution::Normal { mean, .. } => mean,
+        Distribution::LogNormal { mu, .. } => mu.exp(),
+        Distribution::Cauchy { median, .. } => median,
+    };
+    egui::ComboBox::from_label(label)
+        .selected_text(match d {
+            Distribution::Constant(_) => "Постоянное",
+            Distribution::Uniform { .. } => "Равномерное",
+            Distribution::Normal { .. } => "Нормальное",
+            Distribution::LogNormal { .. } => "Логнормальное",
+            Distribution::Cauchy { .. } => "Лоренцево",
+        })
+        .show_ui(ui, |ui| {
+            for (v, name) in [
+                (Distribution::Constant(center), "Постоянное"),
+                (Distribution::Uniform { low: center - 0.05*span, high: center + 0.05*span }, "Равномерное"),
+                (Distribution::Normal { mean: center, std: 0.05*span }, "Нормальное"),
+                (Distribution::LogNormal { mu: center.max(f32::MIN_POSITIVE).ln(), sigma: 0.3 }, "Логнормальное"),
+                (Distribution::Cauchy { median: center, scale: 0.025*span }, "Лоренцево")
+            ]{
+                if ui.selectable_label(std::mem::discriminant(d) == std::mem::discriminant(&v), name).clicked(){
+                    *d = v;
+                }
+            }
+        }
+    );
+    match d{
+        Distribution::Constant(v) => {
+            ui.add(egui::Slider::new(v, range).text("значение"));
+        },
+        Distribution::Uniform { low, high } => {
+            ui.add(egui::Slider::new(low, range.clone()).text("от"));
+            ui.add(egui::Slider::new(high, range).text("до"));
+        },
+        Distribution::Normal { mean, std } => {
+            ui.add(egui::Slider::new(mean, range).text("среднее"));
+            ui.add(egui::Slider::new(std, 0.0..=span).text("отклонение"));
+        },
+        Distribution::LogNormal { mu, sigma } => {
+            ui.add(egui::Slider::new(mu, -3.0..=3.0).text("μ"));
+            ui.add(egui::Slider::new(sigma, 0.0..=3.0).text("σ"));
+        },
+        Distribution::Cauchy { median, scale } => {
+            ui.add(egui::Slider::new(median, range).text("медиана"));
+            ui.add(egui::Slider::new(scale, 0.001*span..=span).logarithmic(true).text("ширина"));
+        },
+    }
+}
+
+/// Parses "dx,dy,w; dx,dy,w" into offsets of a custom stencil
+fn parse_offsets(text: &str) -> Result<Vec<(i32, i32, f32)>, String>{
+    let mut offsets = vec![];
+    for o in text.split(';').map(str::trim).filter(|o| !o.is_empty()){
+        let parts: Vec<&str> = o.split(',').map(str::trim).collect();
+        let [dx, dy, w] = parts[..] else {
+            return Err(format!("нужно три числа в \"{}\"", o));
+        };
+        let dx: i32 = dx.parse().map_err(|_| format!("неверное смещение \"{}\"", dx))?;
+        let dy: i32 = dy.parse().map_err(|_| format!("неверное смещение \"{}\"", dy))?;
+        let w: f32 = w.parse().map_err(|_| format!("неверный вес \"{}\"", w))?;
+        if dx == 0 && dy == 0{
+            return Err("смещение 0,0".to_owned());
+        }
+        offsets.push((dx, dy, w));
+    }
+    if offsets.is_empty(){
+        return Err("нет соседей".to_owned());
     }
+    Ok(offsets)
 }
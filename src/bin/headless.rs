@@ -0,0 +1,192 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+//! Runs the simulation without a window and writes field, polarization and temperature time series to a file.
+//! If the config has a `forc` section, measures first-order reversal curves instead
+//! and writes the FORC distribution.
+//! A run can start from a checkpoint (`checkpoint_in`) and save one at the end (`checkpoint_out`).
+//! PNG maps listed in `images` are imported before the reset.
+//! With a `retention` section it writes a pulse, holds the state and writes the polarization
+//! at logarithmically spaced hold times instead.
+//! `cycles_out` receives the remanent polarization and the share of pinned cells of every period.
+//!
+//! Usage: `headless <config.json> <output.csv>`
+//! or `headless --default-config` to print a config to start from.
+
+use std::{
+    fs,
+    io::{BufReader, BufWriter, Write},
+    process::exit,
+};
+
+use ferroelecrics::{
+    checkpoint::{self, SimRng},
+    forc::{Forc, ForcMap, ForcRun},
+    maps::{CellMap, Channel, MapKind},
+    physics::Simulation,
+    retention::{Retention, RetentionRun},
+};
+use rand::SeedableRng;
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+struct Config {
+    simulation: Simulation,
+    steps: u64,
+    seed: u64,
+    sample_every: u64, // write every n-th step
+    forc: Option<Forc>,
+    retention: Option<Retention>,
+    checkpoint_in: Option<String>, // replaces `simulation` and `seed`
+    checkpoint_out: Option<String>,
+    images: Vec<Image>,
+    cycles_out: Option<String>,
+}
+
+/// PNG map to import
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Image {
+    path: String,
+    #[serde(default)]
+    channel: Channel,
+    kind: MapKind,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            simulation: Simulation::new(100, 100),
+            steps: 10_000,
+            seed: 0,
+            sample_every: 1,
+            forc: None,
+            retention: None,
+            checkpoint_in: None,
+            checkpoint_out: None,
+            images: vec![],
+            cycles_out: None,
+        }
+    }
+}
+
+fn run(config_path: &str, output_path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(config_path)
+        .map_err(|e| format!("can't read {}: {}", config_path, e))?;
+    let config: Config =
+        serde_json::from_str(&text).map_err(|e| format!("bad config {}: {}", config_path, e))?;
+
+    let file = fs::File::create(output_path)
+        .map_err(|e| format!("can't create {}: {}", output_path, e))?;
+    let mut out = BufWriter::new(file);
+
+    let (mut simulation, mut rng) = match &config.checkpoint_in {
+        Some(path) => {
+            let file = fs::File::open(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+            checkpoint::load(BufReader::new(file))?
+        }
+        None => {
+            let mut simulation = config.simulation;
+            for image in &config.images {
+                let file = fs::File::open(&image.path)
+                    .map_err(|e| format!("can't read {}: {}", image.path, e))?;
+                let map = CellMap::from_png(BufReader::new(file), image.channel)
+                    .map_err(|e| format!("{}: {}", image.path, e))?;
+                simulation
+                    .cells
+                    .import_map(image.kind, map)
+                    .map_err(|e| format!("{}: {}", image.path, e))?;
+            }
+            let mut rng = SimRng::seed_from_u64(config.seed);
+            simulation.reset(&mut rng);
+            (simulation, rng)
+        }
+    };
+
+    let write_err = |e: std::io::Error| format!("can't write {}: {}", output_path, e);
+
+    if let Some(forc) = config.forc {
+        let mut run = ForcRun::new(forc);
+        while !run.step(&mut simulation, &mut rng) {}
+        ForcMap::new(&run.curves)
+            .write_csv(&mut out)
+            .map_err(write_err)?;
+    } else if let Some(retention) = config.retention {
+        let mut run = RetentionRun::new(retention);
+        while !run.step(&mut simulation, &mut rng) {}
+        writeln!(out, "time,log10_time,polarization,retained").map_err(write_err)?;
+        for (&(t, p), (log_t, share)) in run.points.iter().zip(run.retained()) {
+            writeln!(out, "{},{},{},{}", t, log_t, p, share).map_err(write_err)?;
+        }
+    } else {
+        let sample_every = config.sample_every.max(1);
+
+        writeln!(out, "step,field,polarization,temperature").map_err(write_err)?;
+        for step in 0..config.steps {
+            simulation.step(&mut rng);
+            if (step + 1) % sample_every == 0 {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    step + 1,
+                    simulation.get_field(),
+                    simulation.get_polarization(),
+                    simulation.get_temperature()
+                )
+                .map_err(write_err)?;
+            }
+        }
+    }
+    out.flush().map_err(write_err)?;
+
+    if let Some(path) = &config.cycles_out {
+        let file = fs::File::create(path).map_err(|e| format!("can't create {}: {}", path, e))?;
+        let mut w = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("can't write {}: {}", path, e);
+        let value = |v: Option<f64>| v.map_or(String::new(), |v| v.to_string());
+        writeln!(w, "cycle,remanent_up,remanent_down,pinned").map_err(write_err)?;
+        for (i, c) in simulation.get_cycles().iter().enumerate() {
+            writeln!(
+                w,
+                "{},{},{},{}",
+                i + 1,
+                value(c.remanent_up),
+                value(c.remanent_down),
+                c.pinned
+            )
+            .map_err(write_err)?;
+        }
+        w.flush().map_err(write_err)?;
+    }
+
+    if let Some(path) = &config.checkpoint_out {
+        let file = fs::File::create(path).map_err(|e| format!("can't create {}: {}", path, e))?;
+        let mut w = BufWriter::new(file);
+        checkpoint::save(&mut w, &simulation, &rng)?;
+        w.flush()
+            .map_err(|e| format!("can't write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.as_slice() {
+        [_, flag] if flag == "--default-config" => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&Config::default()).unwrap()
+            );
+        }
+        [_, config, output] => {
+            if let Err(e) = run(config, output) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        _ => {
+            eprintln!("usage: {} <config.json> <output.csv>", args[0]);
+            eprintln!("       {} --default-config", args[0]);
+            exit(2);
+        }
+    }
+}
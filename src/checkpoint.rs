@@ -0,0 +1,86 @@
+//! Files with the complete state of a run: parameters, lattice and random generator.
+//! A run loaded from a checkpoint continues exactly as the saved one would.
+
+use std::io::{Read, Write};
+
+use crate::physics::{Simulation, SimulationState};
+
+/// Random generator whose state can be saved. Seeded the same way, it gives the same numbers as `rand::rngs::StdRng`.
+pub type SimRng = rand_chacha::ChaCha12Rng;
+
+#[derive(serde::Serialize)]
+struct CheckpointRef<'a> {
+    simulation: &'a Simulation,
+    state: SimulationState,
+    rng: &'a SimRng,
+}
+
+#[derive(serde::Deserialize)]
+struct Checkpoint {
+    simulation: Simulation,
+    state: SimulationState,
+    rng: SimRng,
+}
+
+/// Writes the simulation with its state and the generator as JSON
+pub fn save<W: Write>(w: W, simulation: &Simulation, rng: &SimRng) -> Result<(), String> {
+    let checkpoint = CheckpointRef {
+        simulation,
+        state: simulation.state(),
+        rng,
+    };
+    serde_json::to_writer(w, &checkpoint).map_err(|e| format!("can't write checkpoint: {}", e))
+}
+
+/// Reads what [`save`] has written
+pub fn load<R: Read>(r: R) -> Result<(Simulation, SimRng), String> {
+    let checkpoint: Checkpoint =
+        serde_json::from_reader(r).map_err(|e| format!("bad checkpoint: {}", e))?;
+    let mut simulation = checkpoint.simulation;
+    simulation.set_state(checkpoint.state)?;
+    Ok((simulation, checkpoint.rng))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn run(sim: &mut Simulation, rng: &mut SimRng, steps: usize) -> Vec<f64> {
+        (0..steps)
+            .map(|_| {
+                sim.step(&mut *rng);
+                sim.get_polarization()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loaded_run_continues_as_saved() {
+        let mut rng = SimRng::seed_from_u64(3);
+        let mut sim = Simulation::new(30, 20);
+        sim.reset(&mut rng);
+        run(&mut sim, &mut rng, 700);
+
+        let mut file = vec![];
+        save(&mut file, &sim, &rng).unwrap();
+        let (mut loaded, mut loaded_rng) = load(file.as_slice()).unwrap();
+        assert_eq!(
+            run(&mut loaded, &mut loaded_rng, 1000),
+            run(&mut sim, &mut rng, 1000)
+        );
+    }
+
+    #[test]
+    fn state_of_another_lattice() {
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(30, 20);
+        sim.reset(&mut rng);
+        let state = sim.state();
+        let mut other = Simulation::new(20, 20);
+        other.reset(&mut rng);
+        assert!(other.set_state(state.clone()).is_err());
+        assert!(sim.set_state(state).is_ok());
+    }
+}
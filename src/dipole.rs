@@ -0,0 +1,265 @@
+//! Long-range electrostatic field of the domain pattern: depolarizing and dipole–dipole interaction,
+//! computed as an FFT convolution of the polarization map with the field of a single dipole.
+
+use std::{fmt, sync::Arc};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+/// Parameters of the long-range field
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Depolarization {
+    /// Field of a dipole at a unit distance, in units of the applied field
+    pub strength: f32,
+    /// Screening length in cells, the field decays as `exp(-r/screening)/r³`; 0 for no screening
+    pub screening: f32,
+    /// How much of the mean polarization is compensated by the electrodes
+    pub electrodes: Electrodes,
+}
+
+impl Default for Depolarization {
+    fn default() -> Self {
+        Self {
+            strength: 0.05,
+            screening: 0.0,
+            electrodes: Electrodes::Shorted,
+        }
+    }
+}
+
+/// Electrode boundary condition for the mean polarization
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+pub enum Electrodes {
+    /// Ideal electrodes: only stray fields of the domain pattern remain
+    #[default]
+    Shorted,
+    /// No electrodes: uniform polarization creates the full depolarizing field
+    Open,
+    /// Share `compensation` of the mean polarization is screened (dead layer, imperfect electrodes)
+    Partial {
+        /// Screened share, `0..=1`
+        compensation: f32,
+    },
+}
+
+impl Electrodes {
+    fn compensation(self) -> f32 {
+        match self {
+            Electrodes::Shorted => 1.0,
+            Electrodes::Open => 0.0,
+            Electrodes::Partial { compensation } => compensation,
+        }
+    }
+}
+
+/// Convolution on a fixed grid, planned again when the lattice or the screening changes
+#[derive(Default)]
+pub struct DipoleSolver {
+    plan: Option<Plan>,
+}
+
+impl fmt::Debug for DipoleSolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.plan {
+            Some(plan) => write!(f, "DipoleSolver({}×{})", plan.nx, plan.ny),
+            None => write!(f, "DipoleSolver"),
+        }
+    }
+}
+
+struct Plan {
+    key: (usize, usize, bool, bool, u32), // width, height, periodicity along x and y, screening bits
+    nx: usize,
+    ny: usize,
+    forward: [Arc<dyn Fft<f32>>; 2], // along x and y
+    inverse: [Arc<dyn Fft<f32>>; 2],
+    kernel: Vec<Complex<f32>>, // spectrum of the single dipole field, transposed
+    buffer: Vec<Complex<f32>>,
+    transposed: Vec<Complex<f32>>,
+}
+
+impl DipoleSolver {
+    /// Field at every cell of a `width`×`height` map of polarization projections `p` (row-major).
+    /// Periodic axes wrap around, others are padded with zeros so the edges do not see each other.
+    pub fn field(
+        &mut self,
+        p: &[f32],
+        width: usize,
+        height: usize,
+        periodic: (bool, bool),
+        params: &Depolarization,
+    ) -> Vec<f32> {
+        let key = (
+            width,
+            height,
+            periodic.0,
+            periodic.1,
+            params.screening.to_bits(),
+        );
+        if self.plan.as_ref().map_or(true, |plan| plan.key != key) {
+            self.plan = Some(Plan::new(key));
+        }
+        let plan = self.plan.as_mut().unwrap();
+        let (nx, ny) = (plan.nx, plan.ny);
+
+        let mean = p.iter().sum::<f32>() / p.len() as f32;
+        let compensated = params.electrodes.compensation() * mean;
+        plan.buffer.iter_mut().for_each(|c| *c = Complex::default());
+        for y in 0..height {
+            for x in 0..width {
+                plan.buffer[x + y * nx] = Complex::new(p[x + y * width] - compensated, 0.0);
+            }
+        }
+
+        plan.forward[0].process(&mut plan.buffer);
+        transpose(&plan.buffer, &mut plan.transposed, nx, ny);
+        plan.forward[1].process(&mut plan.transposed);
+        for (c, k) in plan.transposed.iter_mut().zip(&plan.kernel) {
+            *c *= k;
+        }
+        plan.inverse[1].process(&mut plan.transposed);
+        transpose(&plan.transposed, &mut plan.buffer, ny, nx);
+        plan.inverse[0].process(&mut plan.buffer);
+
+        // a dipole field points against the dipole
+        let scale = -params.strength / (nx * ny) as f32;
+        let mut field = Vec::with_capacity(width * height);
+        for y in 0..height {
+            field.extend(
+                plan.buffer[y * nx..y * nx + width]
+                    .iter()
+                    .map(|c| c.re * scale),
+            );
+        }
+        field
+    }
+}
+
+impl Plan {
+    fn new(key: (usize, usize, bool, bool, u32)) -> Self {
+        let (width, height, periodic_x, periodic_y, screening) = key;
+        let screening = f32::from_bits(screening);
+        let nx = if periodic_x { width } else { 2 * width };
+        let ny = if periodic_y { height } else { 2 * height };
+
+        let mut planner = FftPlanner::new();
+        let forward = [planner.plan_fft_forward(nx), planner.plan_fft_forward(ny)];
+        let inverse = [planner.plan_fft_inverse(nx), planner.plan_fft_inverse(ny)];
+
+        // nearest image of every displacement on the grid
+        let wrap = |i: usize, n: usize| {
+            if i <= n / 2 {
+                i as f32
+            } else {
+                i as f32 - n as f32
+            }
+        };
+        let mut kernel = vec![Complex::default(); nx * ny];
+        for y in 0..ny {
+            for x in 0..nx {
+                let r = wrap(x, nx).hypot(wrap(y, ny));
+                if r > 0.0 {
+                    let decay = if screening > 0.0 {
+                        (-r / screening).exp()
+                    } else {
+                        1.0
+                    };
+                    kernel[x + y * nx] = Complex::new(decay / (r * r * r), 0.0);
+                }
+            }
+        }
+        forward[0].process(&mut kernel);
+        let mut transposed = vec![Complex::default(); nx * ny];
+        transpose(&kernel, &mut transposed, nx, ny);
+        forward[1].process(&mut transposed);
+
+        Self {
+            key,
+            nx,
+            ny,
+            forward,
+            inverse,
+            kernel: transposed,
+            buffer: kernel,
+            transposed: vec![Complex::default(); nx * ny],
+        }
+    }
+}
+
+/// Transposes `rows` rows of `n` values into `n` rows of `rows` values
+fn transpose(from: &[Complex<f32>], to: &mut [Complex<f32>], n: usize, rows: usize) {
+    for y in 0..rows {
+        for x in 0..n {
+            to[y + x * rows] = from[x + y * n];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Field of one up dipole in the middle of an empty open 9×9 map
+    fn single_dipole(params: &Depolarization) -> Vec<f32> {
+        let mut p = vec![0.0; 81];
+        p[4 + 4 * 9] = 1.0;
+        DipoleSolver::default().field(&p, 9, 9, (false, false), params)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn dipole_field_opposes_it() {
+        let params = Depolarization {
+            electrodes: Electrodes::Open,
+            ..Default::default()
+        };
+        let field = single_dipole(&params);
+        assert!(
+            close(field[5 + 4 * 9], -params.strength),
+            "{}",
+            field[5 + 4 * 9]
+        );
+        assert!(close(field[6 + 6 * 9], -params.strength / 8f32.powf(1.5)));
+        assert!(close(field[4 + 4 * 9], 0.0));
+
+        let up = DipoleSolver::default().field(&[1.0; 81], 9, 9, (false, false), &params);
+        assert!(up.iter().all(|&e| e < 0.0), "{:?}", up);
+    }
+
+    #[test]
+    fn screening_shortens_the_field() {
+        let params = Depolarization {
+            electrodes: Electrodes::Open,
+            screening: 2.0,
+            ..Default::default()
+        };
+        let field = single_dipole(&params);
+        for r in 1..=4 {
+            let expected = -params.strength * (-(r as f32) / 2.0).exp() / (r * r * r) as f32;
+            assert!(
+                close(field[4 + r + 4 * 9], expected),
+                "{} at {}",
+                field[4 + r + 4 * 9],
+                r
+            );
+        }
+    }
+
+    #[test]
+    fn electrodes_screen_the_mean_polarization() {
+        let field = |electrodes| {
+            let params = Depolarization {
+                electrodes,
+                ..Default::default()
+            };
+            DipoleSolver::default().field(&[1.0; 64], 8, 8, (true, true), &params)
+        };
+        let (shorted, open) = (field(Electrodes::Shorted), field(Electrodes::Open));
+        let partial = field(Electrodes::Partial { compensation: 0.25 });
+        assert!(shorted.iter().all(|&e| close(e, 0.0)), "{:?}", shorted);
+        assert!(open.iter().all(|&e| e < 0.0 && close(e, open[0])));
+        assert!(partial.iter().zip(&open).all(|(&p, &o)| close(p, 0.75 * o)));
+    }
+}
@@ -0,0 +1,212 @@
+//! Quenched disorder: per-cell field offsets, activation barriers and bond strengths
+//! drawn once at reset from configurable distributions.
+
+use rand::Rng;
+use rand_distr::{Cauchy, Distribution as _, LogNormal, Normal};
+
+/// Distribution of a per-cell value
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Distribution {
+    /// Same value everywhere, draws nothing from the generator
+    Constant(f32),
+    /// Equally likely values between `low` and `high`
+    Uniform {
+        /// Smallest value
+        low: f32,
+        /// Largest value
+        high: f32,
+    },
+    /// Gaussian
+    Normal {
+        /// Mean value
+        mean: f32,
+        /// Standard deviation
+        std: f32,
+    },
+    /// `exp` of a normal value with mean `mu` and deviation `sigma`
+    LogNormal {
+        /// Mean of the logarithm
+        mu: f32,
+        /// Standard deviation of the logarithm
+        sigma: f32,
+    },
+    /// Lorentzian, gives the broad switching time spectrum of the NLS model
+    Cauchy {
+        /// Central value
+        median: f32,
+        /// Half width at half maximum
+        scale: f32,
+    },
+}
+
+impl Distribution {
+    /// Draws a value; invalid parameters give the central value
+    pub fn sample<T: Rng>(&self, rng: &mut T) -> f32 {
+        match *self {
+            Distribution::Constant(v) => v,
+            Distribution::Uniform { low, high } => {
+                if low < high {
+                    rng.gen_range(low..high)
+                } else {
+                    low
+                }
+            }
+            Distribution::Normal { mean, std } => {
+                Normal::new(mean, std).map_or(mean, |d| d.sample(rng))
+            }
+            Distribution::LogNormal { mu, sigma } => {
+                LogNormal::new(mu, sigma).map_or(mu.exp(), |d| d.sample(rng))
+            }
+            Distribution::Cauchy { median, scale } => {
+                Cauchy::new(median, scale).map_or(median, |d| d.sample(rng))
+            }
+        }
+    }
+}
+
+/// Distributions of the quenched values; constants of the default leave the cells identical
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Disorder {
+    /// Field added to the applied one
+    pub field: Distribution,
+    /// Factor of the activation exponent, negative values are taken as zero
+    pub barrier: Distribution,
+    /// Factor of the weight passed between neighbours, a bond gets the mean of its two cells;
+    /// negative values are taken as zero
+    pub bond: Distribution,
+}
+
+impl Default for Disorder {
+    fn default() -> Self {
+        Self {
+            field: Distribution::Constant(0.0),
+            barrier: Distribution::Constant(1.0),
+            bond: Distribution::Constant(1.0),
+        }
+    }
+}
+
+impl Disorder {
+    /// Whether all cells get the default values
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Values for `n` cells
+    pub fn draw<T: Rng>(&self, n: usize, rng: &mut T) -> Vec<Quenched> {
+        (0..n)
+            .map(|_| Quenched {
+                field: self.field.sample(rng),
+                barrier: self.barrier.sample(rng).max(0.0),
+                bond: self.bond.sample(rng).max(0.0),
+            })
+            .collect()
+    }
+}
+
+/// Quenched values of one cell
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Quenched {
+    /// Random field added to the local one
+    pub field: f32,
+    /// Factor of the activation barrier
+    pub barrier: f32,
+    /// Factor of the weight passed to and from the cell
+    pub bond: f32,
+}
+
+impl Default for Quenched {
+    fn default() -> Self {
+        Self {
+            field: 0.0,
+            barrier: 1.0,
+            bond: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::checkpoint::SimRng;
+
+    /// Mean and standard deviation of `n` samples of `d`
+    fn moments(d: Distribution, n: usize) -> (f32, f32) {
+        let mut rng = SimRng::seed_from_u64(0);
+        let v: Vec<f32> = (0..n).map(|_| d.sample(&mut rng)).collect();
+        let mean = v.iter().sum::<f32>() / n as f32;
+        let var = v.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n as f32;
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn sampled_statistics() {
+        let (mean, std) = moments(
+            Distribution::Uniform {
+                low: 1.0,
+                high: 3.0,
+            },
+            20_000,
+        );
+        assert!((mean - 2.0).abs() < 0.02 && (std - 1.0 / 3f32.sqrt()).abs() < 0.02);
+
+        let (mean, std) = moments(
+            Distribution::Normal {
+                mean: 0.5,
+                std: 0.2,
+            },
+            20_000,
+        );
+        assert!((mean - 0.5).abs() < 0.01 && (std - 0.2).abs() < 0.01);
+
+        let (mean, _) = moments(
+            Distribution::LogNormal {
+                mu: 0.0,
+                sigma: 0.5,
+            },
+            20_000,
+        );
+        assert!((mean - 0.125f32.exp()).abs() < 0.02, "{mean}");
+
+        // the mean of a Lorentzian is undefined, its quartiles are median ± scale
+        let mut rng = SimRng::seed_from_u64(0);
+        let cauchy = Distribution::Cauchy {
+            median: 1.0,
+            scale: 0.5,
+        };
+        let inside = (0..20_000)
+            .filter(|_| (cauchy.sample(&mut rng) - 1.0).abs() < 0.5)
+            .count();
+        assert!((inside as f32 / 20_000.0 - 0.5).abs() < 0.02, "{inside}");
+
+        let mut rng = SimRng::seed_from_u64(0);
+        assert_eq!(Distribution::Constant(0.3).sample(&mut rng), 0.3);
+        let invalid = Distribution::Normal {
+            mean: 0.5,
+            std: f32::NAN,
+        };
+        assert_eq!(invalid.sample(&mut rng), 0.5);
+    }
+
+    #[test]
+    fn drawn_values_are_clamped() {
+        let disorder = Disorder {
+            field: Distribution::Normal {
+                mean: 0.0,
+                std: 0.1,
+            },
+            barrier: Distribution::Uniform {
+                low: -1.0,
+                high: 1.0,
+            },
+            bond: Distribution::Constant(-2.0),
+        };
+        let cells = disorder.draw(1000, &mut SimRng::seed_from_u64(0));
+        assert!(cells.iter().any(|q| q.field < 0.0));
+        assert!(cells.iter().all(|q| q.barrier >= 0.0 && q.bond == 0.0));
+        assert!(cells.iter().filter(|q| q.barrier == 0.0).count() > 400);
+        assert!(!disorder.is_clean() && Disorder::default().is_clean());
+    }
+}
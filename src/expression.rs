@@ -0,0 +1,372 @@
+//! Formulas entered as text, evaluated in the accumulated neighbour weight `w`,
+//! the field strength `E` and the temperature `T`.
+
+/// Parsed formula that keeps its text, (de)serialized as the text.
+/// Knows `+ - * / ^`, parentheses, numbers, the variables `w`, `E`, `T`, the constants `pi` and `e`
+/// and the functions `sqrt`, `exp`, `ln`, `abs`, `signum`, `floor`, `ceil`, `round`,
+/// `sin`, `cos`, `tan`, `sinh`, `cosh`, `tanh` and `min`, `max` of any number of arguments.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expression {
+    source: String,
+    expr: Node,
+}
+
+impl Expression {
+    /// Parses `source`; unknown names are errors
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.sum()?;
+        if let Some(c) = parser.peek() {
+            return Err(format!("unexpected `{}` at {}", c, parser.pos + 1));
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    /// Text the formula was parsed from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Value at weight `w`, field strength `field` and `temperature`
+    pub fn eval(&self, w: f32, field: f32, temperature: f32) -> f32 {
+        self.expr
+            .eval(&[w as f64, field as f64, temperature as f64]) as f32
+    }
+}
+
+/// Formulas are equal when their texts are
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, String> {
+        Self::parse(&source)
+    }
+}
+
+impl From<Expression> for String {
+    fn from(e: Expression) -> Self {
+        e.source
+    }
+}
+
+/// Syntax tree of a formula
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    /// Index of `w`, `E` or `T`
+    Variable(usize),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(fn(f64) -> f64, Box<Node>),
+    /// `min` (`false`) or `max` (`true`)
+    Extremum(bool, Vec<Node>),
+}
+
+impl Node {
+    fn eval(&self, vars: &[f64; 3]) -> f64 {
+        match self {
+            Node::Number(x) => *x,
+            Node::Variable(i) => vars[*i],
+            Node::Neg(a) => -a.eval(vars),
+            Node::Binary(op, a, b) => {
+                let (a, b) = (a.eval(vars), b.eval(vars));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b),
+                }
+            }
+            Node::Call(f, a) => f(a.eval(vars)),
+            Node::Extremum(max, args) => args
+                .iter()
+                .map(|a| a.eval(vars))
+                .reduce(|a, b| if *max { a.max(b) } else { a.min(b) })
+                .unwrap_or(f64::NAN),
+        }
+    }
+}
+
+/// Deepest syntax tree of a formula, so that neither parsing nor evaluation exhausts the stack
+const MAX_DEPTH: usize = 200;
+
+/// Recursive descent over the characters of a formula, `pos` is the next one
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Depth of the syntax tree built above the current position
+    depth: usize,
+}
+
+impl Parser {
+    /// Goes one level deeper into the syntax tree
+    fn descend(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("formula is nested too deeply at {}", self.pos + 1));
+        }
+        Ok(())
+    }
+
+    /// Next character that is not a space
+    fn peek(&mut self) -> Option<char> {
+        while self
+            .chars
+            .get(self.pos)
+            .map_or(false, |c| c.is_whitespace())
+        {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Takes `c` if it is next
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}` at {}", c, self.pos + 1))
+        }
+    }
+
+    /// Terms joined by `+` and `-`
+    fn sum(&mut self) -> Result<Node, String> {
+        let depth = self.depth;
+        let mut node = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            self.descend()?;
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        self.depth = depth;
+        Ok(node)
+    }
+
+    /// Factors joined by `*` and `/`
+    fn product(&mut self) -> Result<Node, String> {
+        let depth = self.depth;
+        let mut node = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            self.descend()?;
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        self.depth = depth;
+        Ok(node)
+    }
+
+    /// Signs bind weaker than `^`, so `-x^2` is `-(x^2)`
+    fn unary(&mut self) -> Result<Node, String> {
+        self.descend()?;
+        let node = if self.eat('-') {
+            Node::Neg(Box::new(self.unary()?))
+        } else if self.eat('+') {
+            self.unary()?
+        } else {
+            self.power()?
+        };
+        self.depth -= 1;
+        Ok(node)
+    }
+
+    /// Right-associative `^`
+    fn power(&mut self) -> Result<Node, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            Ok(Node::Binary('^', Box::new(base), Box::new(self.unary()?)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let start = self.pos + 1;
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let node = self.sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                if self.peek() == Some('(') {
+                    self.call(&name, start)
+                } else {
+                    match name.as_str() {
+                        "w" => Ok(Node::Variable(0)),
+                        "E" => Ok(Node::Variable(1)),
+                        "T" => Ok(Node::Variable(2)),
+                        "pi" => Ok(Node::Number(std::f64::consts::PI)),
+                        "e" => Ok(Node::Number(std::f64::consts::E)),
+                        _ => Err(format!("unknown variable `{}` at {}", name, start)),
+                    }
+                }
+            }
+            Some(c) => Err(format!("unexpected `{}` at {}", c, start)),
+            None => Err("unexpected end of the formula".to_owned()),
+        }
+    }
+
+    /// Digits with an optional fraction and exponent, like `1.5e-3`
+    fn number(&mut self) -> Result<Node, String> {
+        let start = self.pos;
+        self.take_while(|c| c.is_ascii_digit() || c == '.');
+        let exponent = match (
+            self.chars.get(self.pos),
+            self.chars.get(self.pos + 1),
+            self.chars.get(self.pos + 2),
+        ) {
+            (Some('e'), Some(d), _) if d.is_ascii_digit() => 1,
+            (Some('e'), Some('+' | '-'), Some(d)) if d.is_ascii_digit() => 2,
+            _ => 0,
+        };
+        if exponent > 0 {
+            self.pos += exponent;
+            self.take_while(|c| c.is_ascii_digit());
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse()
+            .map(Node::Number)
+            .map_err(|_| format!("bad number `{}` at {}", text, start + 1))
+    }
+
+    /// Arguments in parentheses of function `name` that starts at `start`
+    fn call(&mut self, name: &str, start: usize) -> Result<Node, String> {
+        self.expect('(')?;
+        let mut args = vec![self.sum()?];
+        while self.eat(',') {
+            args.push(self.sum()?);
+        }
+        self.expect(')')?;
+
+        let f: fn(f64) -> f64 = match name {
+            "min" | "max" => return Ok(Node::Extremum(name == "max", args)),
+            "sqrt" => f64::sqrt,
+            "exp" => f64::exp,
+            "ln" => f64::ln,
+            "abs" => f64::abs,
+            "signum" => f64::signum,
+            "floor" => f64::floor,
+            "ceil" => f64::ceil,
+            "round" => f64::round,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "sinh" => f64::sinh,
+            "cosh" => f64::cosh,
+            "tanh" => f64::tanh,
+            _ => return Err(format!("unknown function `{}` at {}", name, start)),
+        };
+        match <[Node; 1]>::try_from(args) {
+            Ok([a]) => Ok(Node::Call(f, Box::new(a))),
+            Err(_) => Err(format!("`{}` takes one argument", name)),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).map_or(false, |&c| f(c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(source: &str) -> f32 {
+        Expression::parse(source).unwrap().eval(2.0, 0.5, 1.0)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("1 + 2*3"), 7.0);
+        assert_eq!(value("(1 + 2)*3"), 9.0);
+        assert_eq!(value("2^3^2"), 512.0);
+        assert_eq!(value("-w^2"), -4.0);
+        assert_eq!(value("2^-1"), 0.5);
+        assert_eq!(value("8/4/2"), 1.0);
+        assert_eq!(value("1 - 2 - 3"), -4.0);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(value("w*E + T"), 2.0);
+        assert_eq!(value("max(w, E, 3) + min(T)"), 4.0);
+        assert_eq!(value("sqrt(abs(-4))"), 2.0);
+        assert_eq!(value("1.5e-1 * 2e1 + 2*e"), 3.0 + std::f32::consts::E * 2.0);
+        assert!((value("cos(pi)") + 1.0).abs() < 1e-6);
+        assert!(value("ln(-1)").is_nan());
+    }
+
+    #[test]
+    fn errors() {
+        for source in [
+            "",
+            "x",
+            "w +",
+            "(w",
+            "w)",
+            "foo(w)",
+            "sqrt(w, E)",
+            "1.2.3",
+            "w E",
+        ] {
+            assert!(Expression::parse(source).is_err(), "`{source}` is accepted");
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        assert_eq!(
+            value(&format!("{}w{}", "(".repeat(50), ")".repeat(50))),
+            2.0
+        );
+        assert_eq!(value(&format!("{}w", "-".repeat(50))), 2.0);
+        for source in [
+            format!("{}w{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("{}w", "-".repeat(100_000)),
+            format!("w{}", "^w".repeat(100_000)),
+            format!("w{}", "+w".repeat(100_000)),
+            format!("{}w{}", "sqrt(".repeat(100_000), ")".repeat(100_000)),
+        ] {
+            let error = Expression::parse(&source).unwrap_err();
+            assert!(error.starts_with("formula is nested too deeply"), "{error}");
+        }
+    }
+
+    #[test]
+    fn serialized_as_text() {
+        let e = Expression::parse("w^2 * exp(-1/E)").unwrap();
+        let json = serde_json::to_string(&e).unwrap();
+        assert_eq!(json, "\"w^2 * exp(-1/E)\"");
+        assert_eq!(serde_json::from_str::<Expression>(&json).unwrap(), e);
+    }
+}
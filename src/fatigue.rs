@@ -0,0 +1,60 @@
+//! Fatigue and wake-up: switching events pin and release cells, and the remanent polarization
+//! is followed from one period of the field to the next.
+
+use rand::Rng;
+
+/// How switching changes the pinning of a cell
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Fatigue {
+    /// Probability that a free cell becomes pinned when it switches
+    pub pin: f32,
+    /// Probability that a pinned cell is released when it switches
+    pub unpin: f32,
+    /// Factor of the activation exponent of a pinned cell
+    pub strength: f32,
+    /// Share of cells pinned at reset, released by cycling (wake-up)
+    pub initial: f32,
+}
+
+impl Default for Fatigue {
+    fn default() -> Self {
+        Self {
+            pin: 1e-3,
+            unpin: 0.05,
+            strength: 4.0,
+            initial: 0.0,
+        }
+    }
+}
+
+impl Fatigue {
+    /// Pinning of `n` cells at reset
+    pub fn draw<T: Rng>(&self, n: usize, rng: &mut T) -> Vec<bool> {
+        if self.initial > 0.0 {
+            (0..n).map(|_| rng.gen::<f32>() < self.initial).collect()
+        } else {
+            vec![false; n]
+        }
+    }
+
+    /// Pinning of a cell after it switched
+    pub fn switched<T: Rng>(&self, pinned: bool, rng: &mut T) -> bool {
+        let r = rng.gen::<f32>();
+        if pinned {
+            r >= self.unpin
+        } else {
+            r < self.pin
+        }
+    }
+}
+
+/// Polarization at the reversals of one period of the field and the share of pinned cells at its end
+#[derive(Debug, Clone, Copy, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+pub struct CycleRecord {
+    /// At the reversal from up to down, `None` if there was none
+    pub remanent_up: Option<f64>,
+    /// At the reversal from down to up
+    pub remanent_down: Option<f64>,
+    /// Share of pinned cells
+    pub pinned: f64,
+}
@@ -0,0 +1,311 @@
+//! First-order reversal curves: the protocol driving [`Simulation`] and the FORC distribution.
+
+use std::{io, mem::replace};
+
+use rand::Rng;
+
+use crate::physics::{Simulation, Waveform};
+
+/// Parameters of the FORC measurement
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Forc {
+    /// Field that saturates the sample; reversal fields are spread evenly in `(-saturation, saturation]`
+    pub saturation: f32,
+    /// Number of reversal fields
+    pub curves: u32,
+    /// Ticks at saturation before each curve
+    pub hold: u32,
+    /// Ticks for a sweep from `saturation` to `-saturation`
+    pub sweep: u32,
+}
+
+impl Default for Forc {
+    fn default() -> Self {
+        Self {
+            saturation: 0.5,
+            curves: 40,
+            hold: 200,
+            sweep: 1000,
+        }
+    }
+}
+
+impl Forc {
+    /// `k`-th reversal field, from the highest to `-saturation`
+    pub fn reversal_field(&self, k: u32) -> f64 {
+        let s = self.saturation as f64;
+        s - 2.0 * s * (k + 1) as f64 / self.curves.max(1) as f64
+    }
+
+    /// Ticks of a sweep between saturation and the `k`-th reversal field
+    fn ramp(&self, k: u32) -> u32 {
+        let share =
+            (self.saturation as f64 - self.reversal_field(k)) / (2.0 * self.saturation as f64);
+        ((self.sweep as f64 * share).round() as u32).max(1)
+    }
+}
+
+/// Polarization measured while sweeping from a reversal field back to saturation
+#[derive(Debug, Clone)]
+pub struct ForcCurve {
+    /// Field where the sweep turned back
+    pub reversal: f64,
+    /// `(field, polarization)`
+    pub points: Vec<(f64, f64)>,
+}
+
+/// Running FORC measurement. Replaces the field waveform of the simulation until it is finished.
+#[derive(Debug)]
+pub struct ForcRun {
+    /// Parameters of the measurement
+    pub protocol: Forc,
+    /// Curves measured so far
+    pub curves: Vec<ForcCurve>,
+    t: u32,                              // tick inside the current curve
+    ramp: u32,                           // ticks of the sweeps of the current curve
+    saved: Option<(Waveform, f32, f32)>, // user waveform, amplitude and bias
+}
+
+impl ForcRun {
+    /// Measurement that starts at the next step
+    pub fn new(protocol: Forc) -> Self {
+        Self {
+            protocol,
+            curves: vec![],
+            t: 0,
+            ramp: 0,
+            saved: None,
+        }
+    }
+
+    /// Whether all curves are measured
+    pub fn finished(&self) -> bool {
+        self.curves.len() as u32 >= self.protocol.curves && self.t == 0
+    }
+
+    /// Makes one simulation step. Returns `true` when the last curve is measured
+    /// and the waveform of the simulation is restored.
+    pub fn step<T: Rng>(&mut self, sim: &mut Simulation, rng: T) -> bool {
+        if self.finished() {
+            return true;
+        }
+
+        if self.t == 0 {
+            // saturate, go down to the reversal field and back
+            let k = self.curves.len() as u32;
+            self.ramp = self.protocol.ramp(k);
+            let (hold, ramp) = (self.protocol.hold, self.ramp);
+            let reversal = self.protocol.reversal_field(k);
+            let r = (reversal / self.protocol.saturation as f64) as f32;
+            let shape = Waveform::PiecewiseLinear {
+                points: vec![
+                    (0, 1.0),
+                    (hold, 1.0),
+                    (hold + ramp, r),
+                    (hold + 2 * ramp, 1.0),
+                ],
+            };
+
+            let gen = &mut sim.gen;
+            let old_shape = replace(&mut gen.shape, shape);
+            let old_amplitude = replace(&mut gen.amplitude, self.protocol.saturation);
+            let old_bias = replace(&mut gen.bias, 0.0);
+            self.saved
+                .get_or_insert((old_shape, old_amplitude, old_bias));
+            gen.reset();
+
+            self.curves.push(ForcCurve {
+                reversal,
+                points: vec![],
+            });
+        }
+
+        let (hold, ramp) = (self.protocol.hold, self.ramp);
+        sim.step(rng);
+        if self.t >= hold + ramp {
+            self.curves
+                .last_mut()
+                .unwrap()
+                .points
+                .push((sim.get_field() as f64, sim.get_polarization()));
+        }
+
+        self.t += 1;
+        if self.t >= hold + 2 * ramp {
+            self.t = 0;
+            if self.finished() {
+                self.restore(sim);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Gives the simulation its own waveform back; the measured curves are kept
+    pub fn restore(&mut self, sim: &mut Simulation) {
+        if let Some((shape, amplitude, bias)) = self.saved.take() {
+            let gen = &mut sim.gen;
+            gen.shape = shape;
+            gen.amplitude = amplitude;
+            gen.bias = bias;
+            gen.reset();
+        }
+    }
+}
+
+/// FORC distribution `ρ(E, E_r) = -1/2 ∂²P/∂E∂E_r` on a square grid
+#[derive(Debug, Clone)]
+pub struct ForcMap {
+    /// Grid of fields, also used for reversal fields
+    pub fields: Vec<f64>,
+    /// `rho[j][i]` for reversal field `fields[j]` and field `fields[i]`, NaN where `E < E_r`
+    pub rho: Vec<Vec<f64>>,
+}
+
+impl ForcMap {
+    /// Resamples the curves onto a grid of their reversal fields and differentiates
+    pub fn new(curves: &[ForcCurve]) -> Self {
+        let mut curves: Vec<&ForcCurve> = curves.iter().filter(|c| !c.points.is_empty()).collect();
+        curves.sort_by(|a, b| a.reversal.total_cmp(&b.reversal));
+        let fields: Vec<f64> = curves.iter().map(|c| c.reversal).collect();
+        let n = fields.len();
+
+        // p[j][i]: polarization on curve j at field i
+        let p: Vec<Vec<f64>> = curves
+            .iter()
+            .map(|c| {
+                fields
+                    .iter()
+                    .map(|&e| interpolate_at(&c.points, e))
+                    .collect()
+            })
+            .collect();
+
+        let mut rho = vec![vec![f64::NAN; n]; n];
+        for j in 0..n.saturating_sub(1) {
+            for i in 0..n - 1 {
+                let d = p[j + 1][i + 1] - p[j + 1][i] - p[j][i + 1] + p[j][i];
+                let area = (fields[i + 1] - fields[i]) * (fields[j + 1] - fields[j]);
+                rho[j][i] = -0.5 * d / area;
+            }
+        }
+        Self { fields, rho }
+    }
+
+    /// Largest magnitude of the distribution, for colour scaling
+    pub fn max_abs(&self) -> f64 {
+        self.rho
+            .iter()
+            .flatten()
+            .filter(|r| r.is_finite())
+            .fold(0.0, |m, r| r.abs().max(m))
+    }
+
+    /// Writes `E,E_r,rho` lines, skipping points outside the measured region
+    pub fn write_csv<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "E,E_r,rho")?;
+        for (j, row) in self.rho.iter().enumerate() {
+            for (i, r) in row.iter().enumerate() {
+                if r.is_finite() {
+                    writeln!(w, "{},{},{}", self.fields[i], self.fields[j], r)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Polarization at field `e` on a curve sorted by growing field, NaN outside it
+fn interpolate_at(points: &[(f64, f64)], e: f64) -> f64 {
+    let i = points.partition_point(|p| p.0 < e);
+    match (i.checked_sub(1).map(|j| points[j]), points.get(i)) {
+        (Some((e1, p1)), Some(&(e2, p2))) => p1 + (p2 - p1) * (e - e1) / (e2 - e1),
+        // the reversal field itself, recorded in f32
+        (None, Some(&(e2, p2))) if e2 - e <= f32::EPSILON as f64 * e.abs().max(1.0) => p2,
+        _ => f64::NAN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::checkpoint::SimRng;
+
+    fn measure(protocol: Forc) -> Vec<ForcCurve> {
+        let mut rng = SimRng::seed_from_u64(1);
+        let mut sim = Simulation::new(30, 30);
+        sim.reset(&mut rng);
+        let mut run = ForcRun::new(protocol);
+        while !run.step(&mut sim, &mut rng) {}
+        run.curves
+    }
+
+    #[test]
+    fn curves_start_at_reversal_field() {
+        let protocol = Forc {
+            saturation: 0.5,
+            curves: 5,
+            hold: 10,
+            sweep: 100,
+        };
+        let curves = measure(protocol.clone());
+        assert_eq!(curves.len(), 5);
+        for (k, curve) in curves.iter().enumerate() {
+            assert_eq!(curve.reversal, protocol.reversal_field(k as u32));
+            assert!(
+                (curve.points[0].0 - curve.reversal).abs() < 1e-6,
+                "curve {k} starts at {}",
+                curve.points[0].0
+            );
+            assert!(
+                curve.points.windows(2).all(|w| w[1].0 > w[0].0),
+                "curve {k} is not monotonic in field"
+            );
+            assert_eq!(curve.points.len() as u32, protocol.ramp(k as u32));
+        }
+    }
+
+    #[test]
+    fn map_of_curves_linear_in_both_fields() {
+        // P = E + E_r + E*E_r gives rho = -1/2 everywhere
+        let fields = [-1.0, -0.5, 0.0, 0.5, 1.0];
+        let curves: Vec<ForcCurve> = fields
+            .iter()
+            .map(|&r| ForcCurve {
+                reversal: r,
+                points: fields
+                    .iter()
+                    .filter(|&&e| e >= r)
+                    .map(|&e| (e, e + r + e * r))
+                    .collect(),
+            })
+            .collect();
+        let map = ForcMap::new(&curves);
+        assert_eq!(map.fields, fields);
+        for j in 0..fields.len() - 1 {
+            for i in 0..fields.len() - 1 {
+                let rho = map.rho[j][i];
+                if i > j {
+                    assert!((rho + 0.5).abs() < 1e-9, "rho[{j}][{i}] = {rho}");
+                } else {
+                    assert!(
+                        rho.is_nan(),
+                        "rho[{j}][{i}] = {rho} outside the measured region"
+                    );
+                }
+            }
+        }
+        assert!((map.max_abs() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn interpolation_outside_curve() {
+        let points = [(0.0, 0.0), (1.0, 2.0)];
+        assert_eq!(interpolate_at(&points, 0.5), 1.0);
+        assert_eq!(interpolate_at(&points, 0.0), 0.0);
+        assert!(interpolate_at(&points, -0.5).is_nan());
+        assert!(interpolate_at(&points, 1.5).is_nan());
+    }
+}
@@ -0,0 +1,119 @@
+//! Polycrystalline structure: Voronoi grains through the thickness, each with its own polarization axis,
+//! front spreads and nucleation density.
+
+use rand::Rng;
+
+use crate::disorder::Distribution;
+
+/// How the grains are generated at reset
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Grains {
+    /// Number of grains, 0 for a single crystal
+    pub count: u32,
+    /// Angle between the polarization axis and the field, degrees (clamped to `0..90`)
+    pub tilt: Distribution,
+    /// Factors of `x_spread` and `y_spread` (drawn separately)
+    pub spread: Distribution,
+    /// Relative nucleation density, negative values are taken as zero
+    pub nucleation: Distribution,
+    /// Share of the weight passed across a grain boundary
+    pub transmission: f32,
+}
+
+impl Default for Grains {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            tilt: Distribution::Uniform {
+                low: 0.0,
+                high: 45.0,
+            },
+            spread: Distribution::Constant(1.0),
+            nucleation: Distribution::Constant(1.0),
+            transmission: 0.3,
+        }
+    }
+}
+
+/// Properties of one grain
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Grain {
+    /// Cosine of the tilt: share of the field along the axis and of the polarization along the field
+    pub axis: f32,
+    /// Factor of `x_spread` inside the grain
+    pub x_factor: f32,
+    /// Factor of `y_spread` inside the grain
+    pub y_factor: f32,
+    /// Relative nucleation density
+    pub nucleation: f32,
+}
+
+/// Grain of every column of cells and the grains themselves
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct GrainMap {
+    /// Grain indices of a layer, row-major
+    pub cells: Vec<u32>,
+    /// Grains by index
+    pub grains: Vec<Grain>,
+}
+
+impl Grains {
+    /// Voronoi partition of a `width`×`height` layer around random centres, `None` for a single crystal.
+    /// Distances wrap around periodic axes.
+    pub fn generate<T: Rng>(
+        &self,
+        width: usize,
+        height: usize,
+        periodic: (bool, bool),
+        rng: &mut T,
+    ) -> Option<GrainMap> {
+        if self.count == 0 {
+            return None;
+        }
+        let centres: Vec<(f32, f32)> = (0..self.count)
+            .map(|_| {
+                (
+                    rng.gen::<f32>() * width as f32,
+                    rng.gen::<f32>() * height as f32,
+                )
+            })
+            .collect();
+        let grains = (0..self.count)
+            .map(|_| Grain {
+                axis: self.tilt.sample(rng).clamp(0.0, 89.9).to_radians().cos(),
+                x_factor: self.spread.sample(rng).max(0.0),
+                y_factor: self.spread.sample(rng).max(0.0),
+                nucleation: self.nucleation.sample(rng).max(0.0),
+            })
+            .collect();
+
+        let distance = |d: f32, n: usize, periodic: bool| {
+            if periodic {
+                d.abs().min(n as f32 - d.abs())
+            } else {
+                d
+            }
+        };
+        let mut cells = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let nearest = centres
+                    .iter()
+                    .enumerate()
+                    .map(|(k, &(cx, cy))| {
+                        let (dx, dy) = (
+                            distance(px - cx, width, periodic.0),
+                            distance(py - cy, height, periodic.1),
+                        );
+                        (k, dx * dx + dy * dy)
+                    })
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap()
+                    .0;
+                cells.push(nearest as u32);
+            }
+        }
+        Some(GrainMap { cells, grains })
+    }
+}
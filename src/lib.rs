@@ -1,6 +1,22 @@
-#![warn(clippy::all, rust_2018_idioms)]
+#![warn(clippy::all, rust_2018_idioms, missing_docs)]
 
+//! Cellular model of ferroelectric domain switching.
+//!
+//! The simulation core lives in [`physics`] and does not depend on egui.
+//! The interactive application (`App`) is built with the default `gui` feature.
 
+pub mod analysis;
+pub mod checkpoint;
+#[cfg(feature = "gui")]
 mod app;
-mod physics;
+pub mod dipole;
+pub mod disorder;
+pub mod expression;
+pub mod fatigue;
+pub mod forc;
+pub mod grains;
+pub mod maps;
+pub mod physics;
+pub mod retention;
+#[cfg(feature = "gui")]
 pub use app::App;
@@ -0,0 +1,195 @@
+//! Per-cell maps imported from PNG images (painted or measured, e.g. PFM scans):
+//! initial polarization, fixed germ positions and pinning strength.
+
+use std::io::Read;
+
+/// Channel of an image that gives the map values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub enum Channel {
+    /// Red component
+    Red,
+    /// Green component
+    Green,
+    /// Blue component
+    Blue,
+    /// Opacity
+    Alpha,
+    /// Brightness of the colour
+    #[default]
+    Luminance,
+}
+
+/// Quantity set by a map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub enum MapKind {
+    /// Initial polarization: up where the value is above 1/2
+    #[default]
+    Polarization,
+    /// Fixed germs of `StartFixed` instead of random ones: up where the value is above 3/4,
+    /// down where it is between 1/4 and 3/4
+    Germs,
+    /// Pinning strength, multiplies the barrier by `1 + pinning_scale·value`
+    Pinning,
+}
+
+/// Values in `0..=1` on a grid, row-major
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "Grid")]
+pub struct CellMap {
+    /// Columns of the grid
+    pub width: usize,
+    /// Rows of the grid
+    pub height: usize,
+    /// `width·height` values
+    pub values: Vec<f32>,
+}
+
+/// Fields of a [`CellMap`] not checked yet
+#[derive(serde::Deserialize)]
+struct Grid {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl TryFrom<Grid> for CellMap {
+    type Error = String;
+
+    fn try_from(grid: Grid) -> Result<Self, String> {
+        let map = Self {
+            width: grid.width,
+            height: grid.height,
+            values: grid.values,
+        };
+        map.check()?;
+        Ok(map)
+    }
+}
+
+impl CellMap {
+    /// Checks that the grid is not empty and has a value for every node
+    pub fn check(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("map is empty: {}×{}", self.width, self.height));
+        }
+        if self.values.len() != self.width * self.height {
+            return Err(format!(
+                "map has {} values instead of {}×{}",
+                self.values.len(),
+                self.width,
+                self.height
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads `channel` of a PNG image of any colour type and bit depth
+    pub fn from_png<R: Read>(r: R, channel: Channel) -> Result<Self, String> {
+        let mut decoder = png::Decoder::new(r);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| format!("can't read image: {}", e))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buffer)
+            .map_err(|e| format!("can't read image: {}", e))?;
+
+        let samples = info.color_type.samples();
+        let values = buffer[..info.buffer_size()]
+            .chunks_exact(samples)
+            .map(|p| {
+                let [r, g, b, a] = match *p {
+                    [l] => [l, l, l, 255],
+                    [l, a] => [l, l, l, a],
+                    [r, g, b] => [r, g, b, 255],
+                    [r, g, b, a] => [r, g, b, a],
+                    _ => unreachable!("png gives 1 to 4 samples per pixel"),
+                }
+                .map(|c| c as f32 / 255.0);
+                match channel {
+                    Channel::Red => r,
+                    Channel::Green => g,
+                    Channel::Blue => b,
+                    Channel::Alpha => a,
+                    Channel::Luminance => 0.299 * r + 0.587 * g + 0.114 * b,
+                }
+            })
+            .collect();
+        Ok(Self {
+            width: info.width as usize,
+            height: info.height as usize,
+            values,
+        })
+    }
+
+    /// Nearest-neighbour resampling onto a `width`×`height` grid
+    pub fn resample(&self, width: usize, height: usize) -> Self {
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let sy = ((y as f32 + 0.5) * self.height as f32 / height as f32) as usize;
+            for x in 0..width {
+                let sx = ((x as f32 + 0.5) * self.width as f32 / width as f32) as usize;
+                values.push(
+                    self.values[sx.min(self.width - 1) + sy.min(self.height - 1) * self.width],
+                );
+            }
+        }
+        Self {
+            width,
+            height,
+            values,
+        }
+    }
+}
+
+/// Maps applied at every reset, stored already resampled to the lattice
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Maps {
+    /// See [`MapKind::Polarization`]
+    pub polarization: Option<CellMap>,
+    /// See [`MapKind::Germs`]
+    pub germs: Option<CellMap>,
+    /// See [`MapKind::Pinning`]
+    pub pinning: Option<CellMap>,
+    /// Barrier factor at the strongest pinning minus one
+    pub pinning_scale: f32,
+}
+
+impl Default for Maps {
+    fn default() -> Self {
+        Self {
+            polarization: None,
+            germs: None,
+            pinning: None,
+            pinning_scale: 4.0,
+        }
+    }
+}
+
+impl Maps {
+    /// Map of `kind`
+    pub fn get_mut(&mut self, kind: MapKind) -> &mut Option<CellMap> {
+        match kind {
+            MapKind::Polarization => &mut self.polarization,
+            MapKind::Germs => &mut self.germs,
+            MapKind::Pinning => &mut self.pinning,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_maps_are_rejected() {
+        let map = |json| serde_json::from_str::<CellMap>(json);
+        assert!(map(r#"{"width": 2, "height": 1, "values": [0.0, 1.0]}"#).is_ok());
+        assert!(map(r#"{"width": 2, "height": 2, "values": [0.0, 1.0]}"#).is_err());
+        assert!(map(r#"{"width": 0, "height": 1, "values": []}"#).is_err());
+        let maps = r#"{"polarization": {"width": 3, "height": 1, "values": [1.0]}}"#;
+        assert!(serde_json::from_str::<Maps>(maps).is_err());
+    }
+}
@@ -1,20 +1,61 @@
+//! Simulation core: a lattice of cells switched by an alternating field between polarization [`Variants`].
+//!
+//! A cell switches with probability `exp(-1/|E|/f(w))`, where `w` is the weight accumulated
+//! from already switched neighbours and `f` is the [`ActivationFunc`].
+//! Nothing here depends on egui unless the `gui` feature is enabled (for `Simulation::paint`).
 
+use std::{mem::{replace, take}, vec};
 
-use std::{mem::replace, vec};
-
+#[cfg(feature = "gui")]
 use eframe::emath::RectTransform;
-use egui::{Painter, Pos2, Color32, Rect, Vec2, Rounding};
-use fnv::FnvHashMap;
+#[cfg(feature = "gui")]
+use egui::{Painter, Pos2, Color32, Rect, Vec2, Rounding, ColorImage, TextureHandle, TextureOptions, Mesh, Shape};
+use fnv::FnvBuildHasher;
+use indexmap::IndexMap;
 use rand::Rng;
+use rand_distr::{Distribution as _, Poisson};
+
+use crate::{dipole::{Depolarization, DipoleSolver}, disorder::{Disorder, Quenched}, expression::Expression, fatigue::{CycleRecord, Fatigue}, retention::Relaxation, grains::{Grains, GrainMap}, maps::{CellMap, MapKind, Maps}};
 
+/// Active cells with their accumulated weights.
+/// Iterates in insertion order, so a run continues identically after its state is restored.
+type ActiveMap = IndexMap<usize, f32, FnvBuildHasher>;
 
+
+/// Whole model: field source, lattice and the way new domains are nucleated.
+///
+/// Dynamic state is not serialized, call [`Simulation::reset`] after deserializing.
 #[derive( Debug, serde::Deserialize, serde::Serialize)]
 pub struct Simulation{
+    /// Applied field waveform
     pub gen: FieldGenerator,
 
+    /// Lattice of cells
     pub cells: CellBox,
 
-    pub germs: GermGenesis
+    /// Nucleation model
+    pub germs: GermGenesis,
+
+    /// Temperature program
+    #[serde(default)]
+    pub thermostat: Thermostat,
+
+    /// Built-in field
+    #[serde(default)]
+    pub imprint: Imprint,
+
+    #[serde(skip)]
+    field: f32, // applied at the last step
+    #[serde(skip)]
+    temperature: f32, // at the last step
+    #[serde(skip)]
+    tend: FieldTend, // of the last step
+    #[serde(skip)]
+    current: f64, // polarization change per cell at the last step
+    #[serde(skip)]
+    cycles: Vec<CycleRecord>, // completed periods
+    #[serde(skip)]
+    cycle: CycleRecord // current period
 
     // transform: RectTransform,
     // shapes: Vec<Shape>
@@ -22,39 +63,112 @@ pub struct Simulation{
 
 
 impl Simulation{
+    /// Creates `width`×`height` lattice with default parameters
     pub fn new(width: usize, height: usize) -> Self{
         Simulation{cells: CellBox::new(width, height),
-             gen: FieldGenerator { t: 0, time_up: 500, time_down: 500, amplitude: 0.4},
+             gen: FieldGenerator { t: 0, sign: -1.0, time_up: 500, time_down: 500, amplitude: 0.4, bias: 0.0, shape: Waveform::Square, hold: 0, hold_field: 0.0},
              germs: GermGenesis::StartRandom { number: 10 },
+             thermostat: Thermostat::default(),
+             imprint: Imprint::default(),
+             field: 0.0,
+             temperature: 1.0,
+             tend: FieldTend::Stable,
+             current: 0.0,
+             cycles: vec![],
+             cycle: CycleRecord::default()
        }
     }
 
+    /// Advances the simulation by one tick
     pub fn step<T: Rng>(&mut self, mut rng: T){
 
-        let (f, tend) = self.gen.field();
-        self.gen.tick();
-        
-        self.cells.step(f, &tend, &mut rng);
-        self.germs.tick(f, &mut self.cells, &mut rng);
-        match tend {
-            FieldTend::ReverseDown => {self.germs.activate_start(-1.0, &mut self.cells, &mut rng)},
-            FieldTend::ReverseUp => {self.germs.activate_start(1.0, &mut self.cells, &mut rng)},
-            FieldTend::Stable => {},
+        self.cells.refresh_offsets();
+        let (f, tend) = self.gen.field(self.imprint.value());
+        let cycle_completed = self.gen.tick(self.imprint.value());
+        self.field = f;
+        self.tend = tend;
+        self.temperature = self.thermostat.temperature();
+        self.thermostat.tick();
+        let counter = self.cells.polarization_counter;
+
+        if self.thermostat.paraelectric(self.temperature){
+            self.cells.randomize(&mut rng);
+        }
+        else{
+            let e = f + self.imprint.value();
+            let barrier = self.thermostat.barrier(self.temperature);
+            self.cells.step(e, &tend, barrier, self.temperature, &mut rng);
+            if self.cells.relaxation.as_ref().map_or(false, |r| f.abs() <= r.below){
+                self.cells.relax(e, barrier, self.temperature, &mut rng);
+            }
+            self.germs.tick(e, &mut self.cells, &mut rng);
+            match tend {
+                FieldTend::ReverseDown => {self.germs.activate_start(-1.0, &mut self.cells, &mut rng)},
+                FieldTend::ReverseUp => {self.germs.activate_start(1.0, &mut self.cells, &mut rng)},
+                FieldTend::Stable => {},
+            }
+        }
+
+        self.current = (self.cells.polarization_counter - counter)/self.cells.saturation();
+        self.imprint.drift(2.0*self.get_polarization() as f32 - 1.0, cycle_completed);
+
+        match tend{
+            FieldTend::ReverseDown => self.cycle.remanent_up = Some(self.get_polarization()),
+            FieldTend::ReverseUp => self.cycle.remanent_down = Some(self.get_polarization()),
+            FieldTend::Stable => {}
+        }
+        if cycle_completed{
+            self.cycle.pinned = self.cells.pinned_share();
+            self.cycles.push(take(&mut self.cycle));
         }
     }
 
+    /// Net polarization projected onto the field axis, counted from the state with all cells down
+    /// and scaled so that the lattice switched fully up gives 1
     pub fn get_polarization(&self) -> f64{
-        (self.cells.polarization_counter as f64)/((self.cells.width*self.cells.height) as f64)
+        self.cells.polarization_counter/self.cells.saturation()
+    }
+
+    /// Field applied at the last step
+    pub fn get_field(&self) -> f32{
+        self.field
+    }
+
+    /// Temperature at the last step
+    pub fn get_temperature(&self) -> f32{
+        self.temperature
+    }
+
+    /// Whether the field was reversed at the last step
+    pub fn get_tend(&self) -> FieldTend{
+        self.tend
+    }
+
+    /// Switching current: change of [`Simulation::get_polarization`] during the last step
+    pub fn get_current(&self) -> f64{
+        self.current
     }
 
-    /// Call "set_transform" to generate shapes to paint
-    pub fn paint(&self, painter: &Painter, transform: RectTransform) {
+    /// Remanent polarization and pinning of every completed period since reset
+    pub fn get_cycles(&self) -> &[CycleRecord]{
+        &self.cycles
+    }
+
+    /// Paints active cells of `slice` into unit square mapped by `transform`
+    #[cfg(feature = "gui")]
+    pub fn paint(&self, painter: &Painter, transform: RectTransform, slice: Slice) {
+        let active = self.cells.active.iter().filter(|(&i, _)| slice.contains(self.cells.index2coord(i).2));
+        if self.cells.geometry != Geometry::Square{
+            let color = |w: f32| Self::color_gradient(w/4.0, Color32::from_rgb(40, 0, 130), Color32::from_rgb(200, 250, 50));
+            self.paint_polygons(painter, transform, active.map(|(&i, &w)| (i, color(w))));
+            return;
+        }
         
-        for (&i, &color_c) in self.cells.active.iter(){
-            let (x, y) = self.cells.index2coord(i);
+        for (&i, &color_c) in active{
+            let (x, y, _) = self.cells.index2coord(i);
             let x = x as f32 * 0.9 + (self.cells.width as f32)/20.0;
             let y = y as f32 * 0.9 + (self.cells.height as f32)/20.0;
-            let point = transform * Pos2::new((x as f32)/(self.cells.width as f32), (y as f32)/(self.cells.height as f32));
+            let point = transform * Pos2::new(x/(self.cells.width as f32), y/(self.cells.height as f32));
                 painter.rect_filled(Rect::from_center_size(point,
                      transform.scale() * Vec2::new(1.0/self.cells.width as f32, 1.0/self.cells.height as f32)*1.1),
                       Rounding::none(),
@@ -63,72 +177,673 @@ impl Simulation{
         }
     }
 
+    /// Image of a layer or of the projection with one pixel per column of cells, coloured by polarization
+    #[cfg(feature = "gui")]
+    pub fn lattice_image(&self, slice: Slice) -> ColorImage{
+        let pixels = (0..self.cells.width*self.cells.height).map(|i| self.column_color(i, slice)).collect();
+        ColorImage{size: [self.cells.width, self.cells.height], pixels}
+    }
+
+    /// Colour of the cell `i` of the first layer, mixed over layers for a projection
+    #[cfg(feature = "gui")]
+    fn column_color(&self, i: usize, slice: Slice) -> Color32{
+        let layer = self.cells.width*self.cells.height;
+        let variants = &self.cells.variants;
+        let color = match slice{
+            Slice::Layer(z) => Self::VARIANT_COLORS[self.cells.cells[i + z*layer].variant as usize],
+            Slice::Projection => {
+                let up: f32 = (0..self.cells.depth).map(|z| variants.projection(self.cells.cells[i + z*layer].variant)).sum();
+                let up = (up/self.cells.depth as f32/variants.projection(1) + 1.0)/2.0;
+                Self::color_gradient(up, Self::DOWN_COLOR, Self::UP_COLOR)
+            }
+        };
+        if self.cells.grain_boundary(i) {color.linear_multiply(0.6)} else {color}
+    }
+
+    /// Paints polarization of every cell at the same place where [`Simulation::paint`] puts them.
+    /// Square lattices are drawn through `texture` made from [`Simulation::lattice_image`], it is created when needed.
+    #[cfg(feature = "gui")]
+    pub fn paint_lattice(&self, painter: &Painter, transform: RectTransform, slice: Slice, texture: &mut Option<TextureHandle>){
+        if self.cells.geometry != Geometry::Square{
+            let cells = (0..self.cells.width*self.cells.height).map(|i| (i, self.column_color(i, slice)));
+            self.paint_polygons(painter, transform, cells);
+            return;
+        }
+
+        let image = self.lattice_image(slice);
+        let texture = match texture{
+            Some(t) => {t.set(image, TextureOptions::NEAREST); t},
+            None => texture.insert(painter.ctx().load_texture("lattice", image, TextureOptions::NEAREST))
+        };
+        let half_cell = Vec2::new(0.45/self.cells.width as f32, 0.45/self.cells.height as f32);
+        let rect = Rect::from_min_size(Pos2::new(0.05, 0.05) - half_cell, Vec2::splat(0.9));
+        painter.image(texture.id(), transform.transform_rect(rect),
+            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)), Color32::WHITE);
+    }
+
+    /// Paints hexagonal or triangular cells as one mesh
+    #[cfg(feature = "gui")]
+    fn paint_polygons(&self, painter: &Painter, transform: RectTransform, cells: impl Iterator<Item = (usize, Color32)>){
+        let mut mesh = Mesh::default();
+        for (i, color) in cells{
+            let corners = self.cells.cell_polygon(i);
+            let first = mesh.vertices.len() as u32;
+            for &p in corners.iter(){
+                mesh.colored_vertex(transform * Pos2::new(0.05 + 0.9*p.x, 0.05 + 0.9*p.y), color);
+            }
+            for k in 1..corners.len() as u32 - 1{
+                mesh.add_triangle(first, first + k, first + k + 1);
+            }
+        }
+        painter.add(Shape::mesh(mesh));
+    }
+
+    #[cfg(feature = "gui")]
+    const UP_COLOR: Color32 = Color32::from_rgb(90, 150, 200);
+    #[cfg(feature = "gui")]
+    const DOWN_COLOR: Color32 = Color32::from_rgb(20, 30, 60);
+    /// Colours of variants by index: dark for down and bright for up along the field axis
+    #[cfg(feature = "gui")]
+    pub const VARIANT_COLORS: [Color32; 8] = [
+        Self::DOWN_COLOR, Self::UP_COLOR,
+        Color32::from_rgb(150, 60, 40), Color32::from_rgb(220, 140, 50),
+        Color32::from_rgb(50, 100, 40), Color32::from_rgb(140, 200, 70),
+        Color32::from_rgb(90, 40, 100), Color32::from_rgb(200, 130, 210)
+    ];
+
+    #[cfg(feature = "gui")]
     fn color_gradient(v: f32, c1: Color32, c2: Color32) -> Color32{
         let c1 = c1.linear_multiply(1.0 - v);
         let c2 = c2.linear_multiply(v);
         Color32::from_rgb(c1.r() + c2.r(), c1.g() + c2.g(), c1.b() + c2.b())
     }
 
+    /// Copies the dynamic state that is skipped by serialization
+    pub fn state(&self) -> SimulationState{
+        SimulationState{
+            t: self.gen.t,
+            sign: self.gen.sign,
+            variants: self.cells.cells.iter().map(|c| c.variant).collect(),
+            quenched: self.cells.quenched.clone(),
+            grain_map: self.cells.grain_map.clone(),
+            active: self.cells.active.iter().map(|(&i, &w)| (i, w)).collect(),
+            polarization_counter: self.cells.polarization_counter,
+            fixed: if let GermGenesis::StartFixed { fixed, .. } = &self.germs {fixed.clone()} else {vec![]},
+            since_reversal: if let GermGenesis::FieldDriven { since, .. } = self.germs {since} else {0},
+            field: self.field,
+            thermostat_t: self.thermostat.t,
+            imprint: self.imprint.value,
+            pinned: self.cells.pinned.clone(),
+            cycles: self.cycles.clone(),
+            cycle: self.cycle,
+            temperature: self.temperature,
+            tend: self.tend,
+            current: self.current
+        }
+    }
+
+    /// Restores the state taken by [`Simulation::state`] from a simulation with the same lattice size
+    pub fn set_state(&mut self, state: SimulationState) -> Result<(), String>{
+        let size = self.cells.cell_count();
+        if state.variants.len() != size || state.active.iter().map(|a| a.0).chain(state.fixed.iter().copied()).any(|i| i >= size){
+            return Err(format!("state does not fit {}×{}×{} lattice", self.cells.width, self.cells.height, self.cells.depth));
+        }
+        if state.variants.iter().any(|&v| v as usize >= self.cells.variants.count()){
+            return Err("state has variants unknown to the lattice".to_owned());
+        }
+        if !state.quenched.is_empty() && state.quenched.len() != size{
+            return Err("state has disorder of another lattice".to_owned());
+        }
+        if !state.pinned.is_empty() && state.pinned.len() != size{
+            return Err("state has pinning of another lattice".to_owned());
+        }
+        if state.grain_map.as_ref().map_or(false, |m| m.cells.len() != self.cells.width*self.cells.height
+            || m.cells.iter().any(|&g| g as usize >= m.grains.len())){
+            return Err("state has grains of another lattice".to_owned());
+        }
+
+        self.gen.t = state.t;
+        self.gen.sign = state.sign;
+        self.cells.cells = state.variants.into_iter().map(|variant| Cell{variant}).collect();
+        self.cells.quenched = state.quenched;
+        self.cells.grain_map = state.grain_map;
+        self.cells.active = state.active.into_iter().collect();
+        self.cells.polarization_counter = state.polarization_counter;
+        if let GermGenesis::StartFixed { fixed, .. } = &mut self.germs{
+            *fixed = state.fixed;
+        }
+        if let GermGenesis::FieldDriven { since, .. } = &mut self.germs{
+            *since = state.since_reversal;
+        }
+        self.field = state.field;
+        self.thermostat.t = state.thermostat_t;
+        self.imprint.value = state.imprint;
+        self.cells.pinned = state.pinned;
+        self.cycles = state.cycles;
+        self.cycle = state.cycle;
+        self.temperature = state.temperature;
+        self.tend = state.tend;
+        self.current = state.current;
+        self.cells.update_offsets();
+        Ok(())
+    }
+
+    /// Clears the lattice, nucleates fixed germs and restarts the field period
     pub fn reset<T: Rng>(&mut self, mut rng: T){
         self.cells.clear();
+        self.cells.quench(&mut rng);
+        self.cells.pin(&mut rng);
+        self.cells.generate_grains(&mut rng);
+        self.cells.apply_maps();
         self.germs.activate_once(&mut self.cells, &mut rng);
         self.gen.reset();
+        self.thermostat.t = 0;
+        self.imprint.value = self.imprint.field;
+        self.field = 0.0;
+        self.temperature = self.thermostat.temperature();
+        self.tend = FieldTend::Stable;
+        self.current = 0.0;
+        self.cycles.clear();
+        self.cycle = CycleRecord::default();
     }
 }
 
-enum FieldTend{
+/// Dynamic state of a [`Simulation`], see [`Simulation::state`]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct SimulationState{
+    t: u32,
+    sign: f32,
+    variants: Vec<u8>, // of every cell
+    #[serde(default)]
+    quenched: Vec<Quenched>,
+    #[serde(default)]
+    grain_map: Option<GrainMap>,
+    active: Vec<(usize, f32)>, // in iteration order
+    polarization_counter: f64,
+    fixed: Vec<usize>,
+    #[serde(default)]
+    since_reversal: u32,
+    field: f32,
+    #[serde(default)]
+    thermostat_t: u32,
+    #[serde(default)]
+    temperature: f32,
+    #[serde(default)]
+    imprint: f32,
+    #[serde(default)]
+    pinned: Vec<bool>,
+    #[serde(default)]
+    cycles: Vec<CycleRecord>,
+    #[serde(default)]
+    cycle: CycleRecord,
+    tend: FieldTend,
+    current: f64
+}
+
+/// Field state at a step: reversal ticks separate the periods of constant sign
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub enum FieldTend{
+    /// The field turns from positive to negative
     ReverseDown,
+    /// The field turns from negative to positive
     ReverseUp,
+    /// The sign of the field is kept
+    #[default]
     Stable
 }
 
+/// Shape of one field period
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+pub enum Waveform{
+    /// `amplitude` for `time_up` ticks, then `-amplitude` for `time_down` ticks, zero at reversals
+    #[default]
+    Square,
+    /// Positive half-wave of `time_up` ticks and negative one of `time_down` ticks
+    Sine,
+    /// Like [`Waveform::Sine`], but with linear half-waves
+    Triangle,
+    /// Half-waves rising for `rise` ticks and falling for `fall` ticks, flat in between
+    Trapezoid{
+        /// Ticks from zero to the full amplitude
+        rise: u32,
+        /// Ticks from the full amplitude back to zero
+        fall: u32
+    },
+    /// Linear interpolation between `(tick, value)` points sorted by tick, values are multiplied by `amplitude`;
+    /// the period is the tick of the last point, `time_up` and `time_down` are ignored
+    PiecewiseLinear{
+        /// `(tick, value)` nodes
+        points: Vec<(u32, f32)>
+    },
+    /// Positive-Up-Negative-Down: pulses of `width` ticks with signs `+ + - -`,
+    /// each followed by `delay` ticks of zero field; `time_up` and `time_down` are ignored
+    Pund{
+        /// Ticks of a pulse
+        width: u32,
+        /// Ticks of zero field after a pulse
+        delay: u32
+    }
+}
+
+/// Periodic applied field with shape set by [`Waveform`] plus a constant `bias`
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct FieldGenerator{
     #[serde(skip)]
     t: u32,
+    #[serde(skip)]
+    sign: f32, // of the last nonzero field seen by the cells (with the built-in one)
+    /// Ticks of the positive half-wave
     pub time_up: u32,
+    /// Ticks of the negative half-wave
     pub time_down: u32,
-    pub amplitude: f32
+    /// Peak field of the waveform
+    pub amplitude: f32,
+    /// Constant field added to the waveform
+    #[serde(default)]
+    pub bias: f32,
+    /// Waveform of a period
+    #[serde(default)]
+    pub shape: Waveform,
+    /// Ticks of `hold_field` after each half-wave of square, sine, triangular and trapezoidal waveforms
+    #[serde(default)]
+    pub hold: u32,
+    /// Field during a hold, `bias` is added to it
+    #[serde(default)]
+    pub hold_field: f32
 }
 
 impl FieldGenerator{
+    /// Restarts the period
     pub fn reset(&mut self){
         self.t = 0;
+        self.sign = -1.0; // cells start polarized down
     }
-    /// returns field value, than 
-    fn tick(&mut self){
+
+    /// Number of ticks in a period
+    pub fn period(&self) -> u32{
+        match &self.shape{
+            Waveform::Square => self.time_up + self.time_down + 1 + 2*self.hold,
+            Waveform::Sine | Waveform::Triangle | Waveform::Trapezoid { .. } => self.time_up + self.time_down + 2*self.hold,
+            Waveform::PiecewiseLinear { points } => points.last().map_or(1, |p| p.0.max(1)),
+            Waveform::Pund { width, delay } => 4*(width + delay).max(1),
+        }
+    }
+
+    /// Tick of the waveform without holds, `None` inside a hold
+    fn unhold(&self, t: u32) -> Option<u32>{
+        if self.hold == 0 || matches!(self.shape, Waveform::PiecewiseLinear { .. } | Waveform::Pund { .. }){
+            return Some(t);
+        }
+        let end = self.period() - self.hold;
+        match t{
+            t if t < self.time_up => Some(t),
+            t if t < self.time_up + self.hold => None,
+            t if t < end => Some(t - self.hold),
+            _ => None
+        }
+    }
+
+    /// Field at tick `t` of the period
+    pub fn value(&self, t: u32) -> f32{
+        let Some(t) = self.unhold(t) else {return self.hold_field + self.bias};
+        // position inside a half-wave: (time from its start, its length, sign)
+        let half = |t: u32| if t < self.time_up {(t, self.time_up, 1.0)} else {(t - self.time_up, self.time_down, -1.0)};
+
+        let v = match &self.shape{
+            Waveform::Square => match t{
+                0 => 0.0,
+                t if t < self.time_up => self.amplitude,
+                t if t == self.time_up => 0.0,
+                _ => -self.amplitude
+            },
+            Waveform::Sine => {
+                let (t, len, sign) = half(t);
+                sign * self.amplitude * (std::f32::consts::PI * t as f32 / len.max(1) as f32).sin()
+            },
+            Waveform::Triangle => {
+                let (t, len, sign) = half(t);
+                sign * self.amplitude * (1.0 - (2.0 * t as f32 / len.max(1) as f32 - 1.0).abs())
+            },
+            Waveform::Trapezoid { rise, fall } => {
+                let (t, len, sign) = half(t);
+                let up = if *rise == 0 {1.0} else {t as f32 / *rise as f32};
+                let down = if *fall == 0 {1.0} else {len.saturating_sub(t) as f32 / *fall as f32};
+                sign * self.amplitude * up.min(down).clamp(0.0, 1.0)
+            },
+            Waveform::PiecewiseLinear { points } => self.amplitude * interpolate(points, t).unwrap_or(0.0),
+            Waveform::Pund { width, delay } => {
+                let slot = (width + delay).max(1);
+                if t % slot < *width{
+                    [1.0, 1.0, -1.0, -1.0][(t / slot) as usize % 4] * self.amplitude
+                }
+                else{
+                    0.0
+                }
+            }
+        };
+        v + self.bias
+    }
+
+    /// Advances by one tick, returns whether a period is completed
+    fn tick(&mut self, offset: f32) -> bool{
         self.t += 1;
-        if self.t > self.time_down + self.time_up{
+        let completed = self.t >= self.period();
+        if completed{
             self.t = 0;
         }
+        let f = self.value(self.t) + offset;
+        if f != 0.0{
+            self.sign = f.signum();
+        }
+        completed
     }
 
-    fn field(&self) -> (f32, FieldTend){
-        match self.t{
-            0 => (0.0, FieldTend::ReverseUp),
-            t if t < self.time_up => (self.amplitude, FieldTend::Stable),
-            t if t == self.time_up => (0.0, FieldTend::ReverseDown),
-            t if t > self.time_up => (-self.amplitude, FieldTend::Stable),
-            _ => unreachable!()
+    /// Field at current tick; the tick is a reversal if the next one changes the sign of the field plus `offset`
+    fn field(&self, offset: f32) -> (f32, FieldTend){
+        let next = self.value(if self.t + 1 >= self.period() {0} else {self.t + 1}) + offset;
+        let tend = if next > 0.0 && self.sign < 0.0{
+            FieldTend::ReverseUp
         }
+        else if next < 0.0 && self.sign > 0.0{
+            FieldTend::ReverseDown
+        }
+        else{
+            FieldTend::Stable
+        };
+        (self.value(self.t), tend)
     }
 }
 
-type Coord = (usize, usize);
-type Neighbours = [Option<usize>;4];//[usize; 8];
+/// Value at tick `t` of the line through `(tick, value)` points sorted by tick, constant beyond the first and the last one;
+/// `None` without points
+fn interpolate(points: &[(u32, f32)], t: u32) -> Option<f32>{
+    let i = points.partition_point(|p| p.0 <= t);
+    match (i.checked_sub(1).map(|j| points[j]), points.get(i)){
+        (Some((t1, v1)), Some(&(t2, v2))) => Some(v1 + (v2 - v1) * (t - t1) as f32 / (t2 - t1) as f32),
+        (Some((_, v)), None) | (None, Some(&(_, v))) => Some(v),
+        (None, None) => None
+    }
+}
 
+/// Temperature of the sample, constant or following a ramp.
+/// Temperature `T` divides the activation exponent (Arrhenius factor), so `T = 1` leaves the probability as is.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Thermostat{
+    #[serde(skip)]
+    t: u32,
+    /// Temperature when there is no ramp
+    pub temperature: f32,
+    /// `(tick, temperature)` points, linear in between and constant after the last one; empty for no ramp
+    #[serde(default)]
+    pub ramp: Vec<(u32, f32)>,
+    /// Above it cells are paraelectric: their variants are random and domains do not grow.
+    /// Below it the barrier scales as `(1 - T/T_c)²`
+    #[serde(default)]
+    pub curie: Option<f32>
+}
+
+impl Default for Thermostat{
+    fn default() -> Self{
+        Self { t: 0, temperature: 1.0, ramp: vec![], curie: None }
+    }
+}
+
+impl Thermostat{
+    /// Temperature at tick `t` from the reset
+    pub fn value(&self, t: u32) -> f32{
+        interpolate(&self.ramp, t).unwrap_or(self.temperature)
+    }
+
+    /// Temperature at the current tick
+    fn temperature(&self) -> f32{
+        self.value(self.t)
+    }
+
+    fn tick(&mut self){
+        self.t = self.t.saturating_add(1);
+    }
+
+    /// Whether temperature `t` is above the Curie temperature
+    pub fn paraelectric(&self, t: f32) -> bool{
+        self.curie.map_or(false, |curie| t >= curie)
+    }
+
+    /// Factor of the activation exponent at temperature `t`
+    pub fn barrier(&self, t: f32) -> f32{
+        match self.curie{
+            Some(curie) => (1.0 - t/curie).powi(2)/t,
+            None => 1.0/t
+        }
+    }
+}
+
+/// Built-in (imprint) field seen by every cell in addition to the applied one.
+/// It is not a part of the applied field, so it shifts the P–E loop along the field axis.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Imprint{
+    #[serde(skip)]
+    value: f32,
+    /// Built-in field at reset
+    pub field: f32,
+    /// Drift per completed period of the applied field
+    #[serde(default)]
+    pub per_cycle: f32,
+    /// Drift per tick along the mean polarization `2P - 1`, so a state held for long stabilizes itself
+    #[serde(default)]
+    pub per_tick: f32,
+    /// Largest magnitude reached by drifting, 0 for no limit
+    #[serde(default)]
+    pub limit: f32
+}
+
+impl Imprint{
+    /// Built-in field at the current tick
+    pub fn value(&self) -> f32{
+        self.value
+    }
+
+    /// Drifts the field after a tick with mean polarization `p`
+    fn drift(&mut self, p: f32, cycle_completed: bool){
+        let mut v = self.value + self.per_tick*p;
+        if cycle_completed{
+            v += self.per_cycle;
+        }
+        if self.limit > 0.0{
+            v = v.clamp(-self.limit, self.limit);
+        }
+        self.value = v;
+    }
+}
+
+type Coord = (usize, usize, usize);
+
+/// Part of a layered lattice that is painted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slice{
+    /// One layer along z
+    Layer(usize),
+    /// All layers at once
+    Projection
+}
+
+#[cfg(feature = "gui")]
+impl Slice{
+    fn contains(self, z: usize) -> bool{
+        match self{
+            Slice::Layer(layer) => layer == z,
+            Slice::Projection => true
+        }
+    }
+}
+fn one() -> usize{
+    1
+}
+
+fn diagonal_spread() -> f32{
+    0.35
+}
+
+fn lattice_spread() -> [f32; 3]{
+    [1.0, 0.5, 0.5]
+}
+
+fn z_spread() -> f32{
+    1.0
+}
+
+/// Shape of cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub enum Geometry{
+    /// Squares with neighbours set by [`Stencil`]
+    #[default]
+    Square,
+    /// Hexagons with odd rows shifted right by half a cell; 6 neighbours
+    Hexagonal,
+    /// Triangles pointing up where `x + y` is even; 3 neighbours
+    Triangular
+}
+
+impl Geometry{
+    /// Kind of cell at `(x, y)`: rows of hexagons and orientations of triangles alternate
+    fn parity(self, x: usize, y: usize) -> usize{
+        match self{
+            Geometry::Square => 0,
+            Geometry::Hexagonal => y % 2,
+            Geometry::Triangular => (x + y) % 2,
+        }
+    }
+
+    /// Offsets to nearest neighbours with the index of their lattice direction
+    fn neighbours(self, parity: usize) -> &'static [(i32, i32, usize)]{
+        match (self, parity){
+            (Geometry::Hexagonal, 0) => &[(-1, -1, 2), (0, -1, 1), (-1, 0, 0), (1, 0, 0), (-1, 1, 1), (0, 1, 2)],
+            (Geometry::Hexagonal, _) => &[(0, -1, 2), (1, -1, 1), (-1, 0, 0), (1, 0, 0), (0, 1, 1), (1, 1, 2)],
+            (Geometry::Triangular, 0) => &[(-1, 0, 2), (1, 0, 1), (0, 1, 0)],
+            (Geometry::Triangular, _) => &[(0, -1, 0), (-1, 0, 1), (1, 0, 2)],
+            (Geometry::Square, _) => &[]
+        }
+    }
+}
+
+/// Neighbour offset `(dx, dy)` and the weight passed to it
+type Offset = (i32, i32, f32);
+
+/// Neighbour offset `(dx, dy, dz)` in a layered lattice and the weight passed to it
+type LatticeOffset = (i32, i32, i32, f32);
+
+/// Parameters the neighbour offsets are built from: stencil, geometry, depth and the spreads
+type OffsetsKey = (Stencil, Geometry, usize, [f32; 7]);
+
+/// Which cells receive weight from a switched cell
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Stencil{
+    /// Cells with `|dx| + |dy| <= range`
+    VonNeumann{
+        /// Largest distance in steps along the axes
+        range: u32
+    },
+    /// Cells with `max(|dx|, |dy|) <= range`
+    Moore{
+        /// Largest distance along either axis
+        range: u32
+    },
+    /// Arbitrary `(dx, dy, weight)` offsets
+    Custom{
+        /// Offsets with weights used instead of the spreads
+        offsets: Vec<(i32, i32, f32)>
+    }
+}
+
+impl Default for Stencil{
+    fn default() -> Self{
+        Stencil::VonNeumann { range: 1 }
+    }
+}
+
+impl Stencil{
+    /// Offsets with weights: `x_spread` and `y_spread` along the axes, `diagonal_spread` off them,
+    /// divided by the distance in steps for ranges above one
+    pub fn offsets(&self, x_spread: f32, y_spread: f32, diagonal_spread: f32) -> Vec<Offset>{
+        let (range, moore) = match self{
+            Stencil::VonNeumann { range } => (*range as i32, false),
+            Stencil::Moore { range } => (*range as i32, true),
+            Stencil::Custom { offsets } => return offsets.clone(),
+        };
+
+        let mut offsets = vec![];
+        for dy in -range..=range{
+            for dx in -range..=range{
+                let distance = if moore {dx.abs().max(dy.abs())} else {dx.abs() + dy.abs()};
+                if distance == 0 || distance > range{
+                    continue;
+                }
+                let spread = match (dx, dy){
+                    (_, 0) => x_spread,
+                    (0, _) => y_spread,
+                    _ => diagonal_spread
+                };
+                offsets.push((dx, dy, spread / distance as f32));
+            }
+        }
+        offsets
+    }
+}
+
+/// Factors of the weights passed towards `+x`, `-x`, `+y` and `-y`, for each direction of switching.
+/// Offsets off the axes get the mean of their two factors, offsets between layers are not changed.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Anisotropy{
+    /// While switching up (positive field)
+    pub up: [f32; 4],
+    /// While switching down (negative field); `None` to use `up`
+    pub down: Option<[f32; 4]>
+}
+
+impl Default for Anisotropy{
+    fn default() -> Self{
+        Self { up: [1.0; 4], down: None }
+    }
+}
+
+impl Anisotropy{
+    /// Factor of offset `(dx, dy)` while switching along `field`
+    pub fn factor(&self, dx: i32, dy: i32, field: f32) -> f32{
+        let f = if field < 0.0 {self.down.as_ref().unwrap_or(&self.up)} else {&self.up};
+        let fx = match dx.signum() {1 => Some(f[0]), -1 => Some(f[1]), _ => None};
+        let fy = match dy.signum() {1 => Some(f[2]), -1 => Some(f[3]), _ => None};
+        match (fx, fy){
+            (Some(fx), Some(fy)) => (fx + fy)/2.0,
+            (Some(f), None) | (None, Some(f)) => f,
+            (None, None) => 1.0
+        }
+    }
+}
+
+/// Shape `f` of the switching probability `exp(-1/|E|/f(w))` in the accumulated neighbour weight `w`
 #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub enum ActivationFunc{
+    /// `w`
     Linear,
+    /// `w²`
     Quadratic,
+    /// `w³`
     Cubic,
+    /// `√w`
     SquareRoot,
+    /// 1 above `w = 0.5`, 0 below
     Treshold,
-    Switch
+    /// 1 for any positive `w`
+    Switch,
+    /// Formula in `w`, the field strength `E` and the temperature `T`; negative and undefined values are taken as zero
+    Custom{
+        /// Formula of `f`
+        expression: Expression
+    }
 }
 
 impl ActivationFunc{
-    fn func(&self, x: f32) -> f32{
+    /// Value of `f(x)` at field strength `field` and `temperature`
+    pub fn func(&self, x: f32, field: f32, temperature: f32) -> f32{
         match &self {
             ActivationFunc::Linear => {x},
             ActivationFunc::Quadratic => {x*x},
@@ -136,22 +851,78 @@ impl ActivationFunc{
             ActivationFunc::SquareRoot => {x.sqrt()},
             ActivationFunc::Treshold => {if x > 0.5 {1.0} else {0.0}},
             ActivationFunc::Switch => {if x > 0.0 {1.0} else {0.0}},
+            ActivationFunc::Custom { expression } => {
+                let f = expression.eval(x, field, temperature);
+                if f > 0.0 {f} else {0.0}
+            },
         }
     }
 }
 
+/// Nucleation model: where and when new domains appear
 #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub enum GermGenesis{
+    /// `number` random nuclei at every field reversal
     StartRandom{
-        number: u32 // both fixed up and down
+        /// Nuclei per reversal
+        number: u32
     },
+    /// `number` nuclei of each sign chosen at reset, that never lose their activity
     StartFixed{
+        /// Nuclei of each sign
         number: u32,
+        /// Cells of the nuclei, they can't change
         #[serde(skip)]
-        fixed: Vec<usize> // list of those that can't change
+        fixed: Vec<usize>
     },
+    /// One random nucleus per tick with probability `chance`
     ContinuousRandom{
+        /// Probability of a nucleus per tick
         chance: f32
+    },
+    /// Poisson number of random nuclei per tick with mean `rate·law(|E|)·exp(-t/decay)` per cell,
+    /// `t` counts ticks since the last reversal; `decay` 0 keeps the rate constant in time
+    FieldDriven{
+        /// Nuclei per cell and tick at `law` 1
+        rate: f32,
+        /// Dependence on the field strength
+        law: NucleationLaw,
+        /// Ticks of the `e`-fold decrease after a reversal
+        decay: f32,
+        /// Ticks since the last reversal
+        #[serde(skip)]
+        since: u32
+    }
+}
+
+/// Dependence of the nucleation rate on the field strength
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum NucleationLaw{
+    /// Merz law `exp(-alpha/|E|)`
+    Merz{
+        /// Activation field
+        alpha: f32
+    },
+    /// `(|E|/field)^exponent`
+    Power{
+        /// Field of the unit rate
+        field: f32,
+        /// Power of the field
+        exponent: f32
+    }
+}
+
+impl NucleationLaw{
+    /// Rate factor at field strength `field`
+    pub fn factor(&self, field: f32) -> f32{
+        let field = field.abs();
+        if field == 0.0{
+            return 0.0;
+        }
+        match *self{
+            NucleationLaw::Merz { alpha } => (-alpha/field).exp(),
+            NucleationLaw::Power { field: scale, exponent } => if scale > 0.0 {(field/scale).powf(exponent)} else {0.0},
+        }
     }
 }
 
@@ -159,6 +930,21 @@ impl GermGenesis{
     /// should be called at start of generation or reset (creates and saves fixed)
     pub fn activate_once<T: Rng>(&mut self, cells: &mut CellBox, rng: &mut T){ 
         if let Self::StartFixed { number, ref mut fixed } = self {
+            if let Some(map) = &cells.maps.germs{
+                // seeds at the marked columns: up on light, down on grey
+                let map = map.resample(cells.width, cells.height);
+                *fixed = vec![];
+                for i in 0..cells.cell_count(){
+                    let field = match map.values[i % map.values.len()]{
+                        v if v > 0.75 => 1.0,
+                        v if v > 0.25 => -1.0,
+                        _ => continue
+                    };
+                    cells.nucleate(i, field, rng);
+                    fixed.push(i);
+                }
+                return;
+            }
             *fixed = vec![];
             for field in [-1.0, 1.0]{
                 for _ in 0..*number{
@@ -166,8 +952,12 @@ impl GermGenesis{
                 }
             }
         }
+        else if let Self::FieldDriven { since, .. } = self {
+            *since = 0;
+        }
     }
 
+    /// Creates [`GermGenesis::StartFixed`] and places its germs
     pub fn new_fixed<T: Rng>(cells: &mut CellBox, rng: &mut T, n: u32) -> Self{
         let mut s = Self::StartFixed { number: n, fixed: Vec::with_capacity((2*n).try_into().unwrap()) };
         s.activate_once(cells, rng);
@@ -181,6 +971,9 @@ impl GermGenesis{
                 cells.random_activate(rng, field);
             }
         }
+        else if let Self::FieldDriven { since, .. } = self {
+            *since = 0;
+        }
     }
 
     /// should be called each tick (prevents destroying for fixed and creates new for continuous)
@@ -196,138 +989,717 @@ impl GermGenesis{
                 cells.random_activate(rng, field);
             }
         }
+        else if let Self::FieldDriven { rate, law, decay, since } = self {
+            let t = *since as f32;
+            *since = since.saturating_add(1);
+            let decay = if *decay > 0.0 {(-t/ *decay).exp()} else {1.0};
+            let mean = *rate*law.factor(field)*decay*cells.cell_count() as f32;
+            if mean > 0.0 && mean.is_finite(){
+                let number = Poisson::new(mean.min(cells.cell_count() as f32) as f64).map_or(0.0, |d| d.sample(rng));
+                for _ in 0..number as u64{
+                    cells.random_activate(rng, field);
+                }
+            }
+        }
     }
 }
 
+/// Rectangular lattice of cells and the set of active (switching) cells with their accumulated weights
 #[derive(Debug,serde::Deserialize, serde::Serialize)]
 pub struct CellBox{
     #[serde(skip)]
     cells: Vec<Cell>,
     #[serde(skip)]
-    active: FnvHashMap<usize, f32>,
+    active: ActiveMap,
 
     #[serde(skip)]
-    polarization_counter: i32,
+    polarization_counter: f64, // sum of projection changes since all cells were down
 
+    /// Cells along x
     pub width: usize,
+    /// Cells along y
     pub height: usize,
+    /// Number of layers through the thickness
+    #[serde(default = "one")]
+    pub depth: usize,
+
+    /// Edges along x (left and right)
+    #[serde(default)]
+    pub boundary_x: Boundary,
+    /// Edges along y (top and bottom)
+    #[serde(default)]
+    pub boundary_y: Boundary,
+    /// Edges along z (electrodes)
+    #[serde(default)]
+    pub boundary_z: Boundary,
 
+    /// Weight passed to neighbours along x of a switched cell
     pub x_spread: f32,
+    /// Weight passed to neighbours along y of a switched cell
     pub y_spread: f32,
-    pub activation_func: ActivationFunc
+    /// Weight passed to neighbours off the axes
+    #[serde(default = "diagonal_spread")]
+    pub diagonal_spread: f32,
+    /// Weight passed to neighbours in adjacent layers
+    #[serde(default = "z_spread")]
+    pub z_spread: f32,
+    /// Direction-dependent factors of the in-plane spreads; `None` for growth symmetric under reflection
+    #[serde(default)]
+    pub anisotropy: Option<Anisotropy>,
+    /// Neighbours of a square cell, other geometries have their own
+    #[serde(default)]
+    pub stencil: Stencil,
+    /// Shape of cells
+    #[serde(default)]
+    pub geometry: Geometry,
+    /// Weights along the three directions of hexagonal and triangular lattices:
+    /// horizontal, rising and falling for hexagons; across horizontal, rising and falling edges for triangles
+    #[serde(default = "lattice_spread")]
+    pub lattice_spread: [f32; 3],
+    #[serde(skip)]
+    offsets: Vec<[Vec<LatticeOffset>; 2]>, // for every grain and cells of both parities
+    #[serde(skip)]
+    offsets_built: Option<OffsetsKey>, // parameters of `offsets`
+    /// Dependence of the switching probability on the accumulated weight
+    pub activation_func: ActivationFunc,
+    /// Polarization directions a cell can take
+    #[serde(default)]
+    pub variants: Variants,
+    /// Long-range field of the domain pattern added to the applied one; `None` to leave it out
+    #[serde(default)]
+    pub depolarization: Option<Depolarization>,
+    #[serde(skip)]
+    dipole: DipoleSolver,
+    /// Distributions of per-cell field offsets, barriers and bonds
+    #[serde(default)]
+    pub disorder: Disorder,
+    #[serde(skip)]
+    quenched: Vec<Quenched>, // drawn from `disorder` at reset, empty if it is clean
+    /// Pinning and release of cells by switching; `None` for no fatigue
+    #[serde(default)]
+    pub fatigue: Option<Fatigue>,
+    #[serde(skip)]
+    pinned: Vec<bool>, // empty without fatigue
+    /// Switching along the local field during holds; `None` to freeze the cells at weak applied field
+    #[serde(default)]
+    pub relaxation: Option<Relaxation>,
+    /// Polycrystalline structure
+    #[serde(default)]
+    pub grains: Grains,
+    #[serde(skip)]
+    grain_map: Option<GrainMap>, // generated from `grains` at reset, `None` for a single crystal
+    /// Imported maps applied at reset
+    #[serde(default)]
+    pub maps: Maps
+}
+
+/// What lies beyond an edge of the lattice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub enum Boundary{
+    /// Nothing: edge cells have fewer neighbours
+    #[default]
+    Open,
+    /// The opposite edge
+    Periodic,
+    /// Mirror image of the lattice about the edge cells
+    Reflecting
+}
+
+impl Boundary{
+    /// Maps coordinate `c` onto `0..n`, `None` if it is outside an open edge
+    fn wrap(self, c: i32, n: usize) -> Option<usize>{
+        let n = n as i32;
+        if (0..n).contains(&c){
+            return Some(c as usize);
+        }
+        match self{
+            Boundary::Open => None,
+            Boundary::Periodic => Some(c.rem_euclid(n) as usize),
+            Boundary::Reflecting => {
+                if n == 1{
+                    return Some(0);
+                }
+                let m = c.rem_euclid(2*(n - 1));
+                Some((if m < n {m} else {2*(n - 1) - m}) as usize)
+            }
+        }
+    }
+
+    /// Coordinates in `0..n` that [`Boundary::wrap`] maps to `c` from `d` back, so the ones receiving what `c` passes by `d`.
+    /// Near a reflecting edge there may be several of them, or `c + d` itself may receive twice
+    fn receivers(self, c: usize, d: i32, n: usize) -> Vec<usize>{
+        match self{
+            Boundary::Reflecting if n > 1 => {
+                let (c, n) = (c as i32, n as i32);
+                let period = 2*(n - 1);
+                let turns = d.abs()/period + 1;
+                // images of an edge cell coincide with their mirror images
+                let mirrored = c != 0 && c != n - 1;
+                (-turns..=turns).flat_map(|k| [Some(c + k*period), mirrored.then_some(k*period - c)])
+                    .flatten().map(|m| m + d).filter(|t| (0..n).contains(t)).map(|t| t as usize).collect()
+            }
+            _ => self.wrap(c as i32 + d, n).into_iter().collect(),
+        }
+    }
+
+    /// Whether `c + d` may differ from the only receiver of what `c` passes by `d`, see [`Boundary::receivers`]
+    fn mirrors(self, c: usize, d: i32, n: usize) -> bool{
+        self == Boundary::Reflecting && !(d.abs() < c as i32 && c as i32 + d.abs() + 1 < n as i32)
+    }
+}
+
+/// Set of polarization variants of a cell. Variants 0 and 1 are down and up along the field axis (z).
+/// A transition to a variant with a larger projection onto the field is made with probability
+/// `exp(-1/(r·|E|·f(w)))`, where the rate `r` depends on the angle between the variants; zero rate forbids it.
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+pub enum Variants{
+    /// Up and down only, 180° switching
+    #[default]
+    Uniaxial,
+    /// Six variants along ±z, ±x, ±y; in-plane ones have no projection and are intermediate steps of 90° switching
+    Tetragonal{
+        /// Rate of switching to the opposite variant
+        rate_180: f32,
+        /// Rate of switching to a perpendicular variant
+        rate_90: f32
+    },
+    /// Eight variants along the cube diagonals, odd ones are up; projection is `1/√3`
+    Rhombohedral{
+        /// Rate of switching to a variant differing in one component
+        rate_71: f32,
+        /// Rate of switching to a variant differing in two components
+        rate_109: f32,
+        /// Rate of switching to the opposite variant
+        rate_180: f32
+    }
+}
+
+impl Variants{
+    /// Number of variants
+    pub fn count(&self) -> usize{
+        match self{
+            Variants::Uniaxial => 2,
+            Variants::Tetragonal { .. } => 6,
+            Variants::Rhombohedral { .. } => 8,
+        }
+    }
+
+    /// Direction `(x, y, z)` of variant `v`
+    pub fn direction(&self, v: u8) -> [i32; 3]{
+        match self{
+            Variants::Uniaxial => [0, 0, 2*v as i32 - 1],
+            Variants::Tetragonal { .. } => [[0, 0, -1], [0, 0, 1], [1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0]][v as usize],
+            Variants::Rhombohedral { .. } => [(v >> 1 & 1) as i32, (v >> 2 & 1) as i32, (v & 1) as i32].map(|b| 2*b - 1),
+        }
+    }
+
+    /// Name of variant `v` like `+x−z`
+    pub fn label(&self, v: u8) -> String{
+        self.direction(v).iter().zip(["x", "y", "z"]).filter(|(&d, _)| d != 0)
+            .map(|(&d, axis)| format!("{}{}", if d > 0 {"+"} else {"−"}, axis)).collect()
+    }
+
+    /// Projection of variant `v` onto the field axis, in units of spontaneous polarization
+    pub fn projection(&self, v: u8) -> f32{
+        let z = self.direction(v)[2] as f32;
+        if let Variants::Rhombohedral { .. } = self {z/3f32.sqrt()} else {z}
+    }
+
+    /// Whether variant `v` can gain projection along `field`
+    fn opposes(&self, v: u8, field: f32) -> bool{
+        field != 0.0 && self.projection(v)*field <= 0.0
+    }
+
+    /// Rate of the transition between variants
+    fn rate(&self, from: u8, to: u8) -> f32{
+        let (a, b) = (self.direction(from), self.direction(to));
+        let dot: i32 = a.iter().zip(b).map(|(a, b)| a*b).sum();
+        match *self{
+            Variants::Uniaxial => 1.0,
+            Variants::Tetragonal { rate_180, rate_90 } => if dot < 0 {rate_180} else {rate_90},
+            Variants::Rhombohedral { rate_71, rate_109, rate_180 } => match dot {1 => rate_71, -1 => rate_109, _ => rate_180},
+        }
+    }
+
+    /// Variants with a larger projection along `field` than `v`, with transition rates
+    fn transitions(&self, v: u8, field: f32) -> impl Iterator<Item = (u8, f32)> + '_{
+        let gain = move |u: u8| self.projection(u)*field.signum();
+        (0..self.count() as u8).filter(move |&u| gain(u) > gain(v)).map(move |u| (u, self.rate(v, u)))
+    }
+
+    /// Variant taken by a nucleus growing along `field` from `v`: the nearest one with projection along the field
+    fn aligned(&self, v: u8, field: f32) -> u8{
+        let up = (field > 0.0) as u8;
+        match self{
+            Variants::Rhombohedral { .. } => v & !1 | up,
+            _ => up
+        }
+    }
+
+    /// Polarization change when a cell goes from `from` to `to`, counted so that flipping down to up gives 1
+    fn charge(&self, from: u8, to: u8) -> f64{
+        ((self.projection(to) - self.projection(from))/(2.0*self.projection(1))) as f64
+    }
 }
 
 #[derive(Debug, Clone)]
 struct Cell{
-    polarization: bool
+    variant: u8
 }
 
 impl Cell{
     fn new() -> Self{
-        Self { polarization: false }
-    }
-    fn get_polarization(&self) -> i32{
-        return if self.polarization {1} else {-1}
+        Self { variant: 0 }
     }
 
-    fn activation<T: Rng>(&self, pol_coeff: f32, field: f32, rng: &mut T, func: &ActivationFunc) -> bool{
+    /// Variant the cell switches to, if it does; `shape` is the value of [`ActivationFunc`], `barrier` multiplies the activation exponent
+    fn activation<T: Rng>(&self, shape: f32, field: f32, barrier: f32, rng: &mut T, variants: &Variants) -> Option<u8>{
         let r = rng.gen::<f32>();
-        r < (-1.0/field.abs()/func.func(pol_coeff)).exp()
+        let exponent = -barrier/field.abs()/shape;
+        let mut probability = 0.0;
+        for (target, rate) in variants.transitions(self.variant, field){
+            probability += (exponent/rate).exp();
+            if r < probability{
+                return Some(target);
+            }
+        }
+        None
     }
 }
 
 impl CellBox{
 
+    /// Polarizes every cell down and deactivates it
     pub fn clear(&mut self){
         let init: Cell = Cell::new();
-        self.cells = vec![init; self.width*self.height];
+        self.cells = vec![init; self.cell_count()];
         self.active.clear();
-        self.polarization_counter = 0;
+        self.polarization_counter = 0.0;
+        self.update_offsets();
+    }
+
+    fn update_offsets(&mut self){
+        let factors = self.grain_map.as_ref().map_or(vec![(1.0, 1.0)], |m| m.grains.iter().map(|g| (g.x_factor, g.y_factor)).collect());
+        self.offsets = factors.into_iter().map(|f| self.grain_offsets(f)).collect();
+        self.offsets_built = Some(self.offsets_key());
+    }
+
+    fn offsets_key(&self) -> OffsetsKey{
+        let spreads = [self.x_spread, self.y_spread, self.diagonal_spread, self.z_spread, self.lattice_spread[0], self.lattice_spread[1], self.lattice_spread[2]];
+        (self.stencil.clone(), self.geometry, self.depth, spreads)
     }
 
-    fn new(width: usize, height: usize) -> Self{
+    /// Builds the offsets again if their parameters were changed since
+    fn refresh_offsets(&mut self){
+        if self.offsets_built.as_ref() != Some(&self.offsets_key()){
+            self.update_offsets();
+        }
+    }
+
+    /// Offsets with in-plane spreads multiplied by the `(x, y)` factors of a grain
+    fn grain_offsets(&self, (fx, fy): (f32, f32)) -> [Vec<LatticeOffset>; 2]{
+        let in_plane: [Vec<Offset>; 2] = if self.geometry == Geometry::Square{
+            [self.stencil.offsets(self.x_spread*fx, self.y_spread*fy, self.diagonal_spread*(fx + fy)/2.0), vec![]]
+        }
+        else{
+            // the first direction is horizontal, the others are mostly vertical
+            let factors = [fx, fy, fy];
+            [0, 1].map(|parity| self.geometry.neighbours(parity).iter()
+                .map(|&(dx, dy, d)| (dx, dy, self.lattice_spread[d]*factors[d])).collect())
+        };
+        let through = if self.depth > 1 {vec![(0, 0, -1, self.z_spread), (0, 0, 1, self.z_spread)]} else {vec![]};
+        in_plane.map(|o| o.into_iter().map(|(dx, dy, w)| (dx, dy, 0, w)).chain(through.iter().copied()).collect())
+    }
+
+    /// Generates grains from [`CellBox::grains`]
+    pub fn generate_grains<T: Rng>(&mut self, rng: &mut T){
+        let periodic = (self.boundary_x == Boundary::Periodic, self.boundary_y == Boundary::Periodic);
+        self.grain_map = self.grains.generate(self.width, self.height, periodic, rng);
+        self.update_offsets();
+    }
+
+    /// Grain of cell `i`, 0 for a single crystal
+    fn grain(&self, i: usize) -> usize{
+        self.grain_map.as_ref().map_or(0, |m| m.cells[i % (self.width*self.height)] as usize)
+    }
+
+    /// Share of the field along the polarization axis of cell `i`
+    fn axis(&self, i: usize) -> f32{
+        self.grain_map.as_ref().map_or(1.0, |m| m.grains[self.grain(i)].axis)
+    }
+
+    /// Whether the cell `i` of a layer lies on a grain boundary (its right or lower neighbour is in another grain)
+    #[cfg(feature = "gui")]
+    fn grain_boundary(&self, i: usize) -> bool{
+        let Some(m) = &self.grain_map else {return false};
+        let (x, y) = (i % self.width, i / self.width);
+        (x + 1 < self.width && m.cells[i + 1] != m.cells[i]) || (y + 1 < self.height && m.cells[i + self.width] != m.cells[i])
+    }
+
+    /// Number of cells
+    pub fn cell_count(&self) -> usize{
+        self.width*self.height*self.depth
+    }
+
+    /// Polarization per cell of layer `z`, measured like [`Simulation::get_polarization`]
+    pub fn layer_polarization(&self, z: usize) -> f64{
+        let layer = self.width*self.height;
+        let p: f64 = (z*layer..(z + 1)*layer).map(|i| self.variants.charge(0, self.cells[i].variant)*self.axis(i) as f64).sum();
+        p*self.depth as f64/self.saturation()
+    }
+
+    /// Polarization change when every cell goes from down to up: the cell count scaled by the grain axes
+    fn saturation(&self) -> f64{
+        self.grain_map.as_ref().map_or(self.cell_count() as f64, |m| {
+            m.cells.iter().map(|&g| m.grains[g as usize].axis as f64).sum::<f64>()*self.depth as f64
+        }).max(f64::MIN_POSITIVE)
+    }
+
+    /// Corners of a hexagonal or triangular cell, the lattice fills the unit square
+    #[cfg(feature = "gui")]
+    fn cell_polygon(&self, i: usize) -> Vec<Pos2>{
+        let (x, y, _) = self.index2coord(i);
+        let (x, y, w, h) = (x as f32, y as f32, self.width as f32, self.height as f32);
+        match self.geometry{
+            Geometry::Hexagonal => {
+                // rows overlap by a quarter of hexagon height
+                let half_width = 0.5/(w + 0.5);
+                let radius = 2.0/3.0/h;
+                let c = Pos2::new((x + 0.5 + 0.5*(y as usize % 2) as f32)/(w + 0.5), (y + 0.5)/h);
+                [(0.0, -1.0), (1.0, -0.5), (1.0, 0.5), (0.0, 1.0), (-1.0, 0.5), (-1.0, -0.5)]
+                    .map(|(dx, dy)| c + Vec2::new(dx*half_width, dy*radius)).to_vec()
+            },
+            Geometry::Triangular => {
+                let base = 2.0/(w + 1.0);
+                let left = x*base/2.0;
+                let (top, bottom) = (y/h, (y + 1.0)/h);
+                if self.geometry.parity(x as usize, y as usize) == 0{
+                    vec![Pos2::new(left + base/2.0, top), Pos2::new(left + base, bottom), Pos2::new(left, bottom)]
+                }
+                else{
+                    vec![Pos2::new(left, top), Pos2::new(left + base, top), Pos2::new(left + base/2.0, bottom)]
+                }
+            },
+            Geometry::Square => {
+                let (x, y) = (x/w, y/h);
+                vec![Pos2::new(x, y), Pos2::new(x + 1.0/w, y), Pos2::new(x + 1.0/w, y + 1.0/h), Pos2::new(x, y + 1.0/h)]
+            }
+        }
+    }
+
+    /// Creates `width`×`height` lattice with one layer and default spreads
+    pub fn new(width: usize, height: usize) -> Self{
         let init: Cell = Cell::new();
-        Self { cells: vec![init; width*height],
-             active: FnvHashMap::default(),
-             width, height,
-             polarization_counter: 0,
+        let mut cells = Self { cells: vec![init; width*height],
+             active: ActiveMap::default(),
+             width, height, depth: 1,
+             polarization_counter: 0.0,
+            boundary_x: Boundary::Open,
+            boundary_y: Boundary::Open,
+            boundary_z: Boundary::Open,
             x_spread: 1.0,
             y_spread: 0.5,
-            activation_func: ActivationFunc::Quadratic, }
+            diagonal_spread: diagonal_spread(),
+            z_spread: z_spread(),
+            anisotropy: None,
+            stencil: Stencil::default(),
+            geometry: Geometry::Square,
+            lattice_spread: lattice_spread(),
+            offsets: vec![],
+            offsets_built: None,
+            activation_func: ActivationFunc::Quadratic,
+            variants: Variants::Uniaxial,
+            depolarization: None,
+            dipole: DipoleSolver::default(),
+            disorder: Disorder::default(),
+            quenched: vec![],
+            fatigue: None,
+            pinned: vec![],
+            relaxation: None,
+            grains: Grains::default(),
+            grain_map: None,
+            maps: Maps::default() };
+        cells.update_offsets();
+        cells
     }
 
-    fn bool2charge(b: bool) -> i32{
-        return if b {1} else {-1}
+    /// Whether the cell at `(x, y, z)` is polarized up
+    pub fn polarization_at(&self, x: usize, y: usize, z: usize) -> bool{
+        self.variants.projection(self.variant_at(x, y, z)) > 0.0
     }
 
-    fn index2coord(&self, i: usize) -> Coord{
-        (i%self.width, i/self.width)
+    /// Variant of the cell at `(x, y, z)`, see [`Variants`]
+    pub fn variant_at(&self, x: usize, y: usize, z: usize) -> u8{
+        self.cells[x + (y + z*self.height)*self.width].variant
     }
 
-    fn coord2index(&self, (x, y): (i32, i32)) -> Option<usize>{
-        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height{
-            return None;
-        }
-        Some(x as usize + y as usize*self.width)
+    fn index2coord(&self, i: usize) -> Coord{
+        (i%self.width, i/self.width%self.height, i/self.width/self.height)
     }
 
-    fn get_neighbours(&self, i: usize) -> Neighbours{
-        let (x, y) = self.index2coord(i);
-        let x = x as i32;
-        let y = y as i32;
-        [/*(x-1, y-1),*/ (x, y-1), /*(x+1, y-1),*/
-         (x-1, y), (x+1, y),
-         /*(x-1, y+1),*/ (x, y+1), /*(x+1, y+1)*/].map(|s| self.coord2index(s))
+    /// Index of the cell at `(x, y, z)`, coordinates outside the box are mapped according to the boundaries
+    fn coord2index(&self, (x, y, z): (i32, i32, i32)) -> Option<usize>{
+        let x = self.boundary_x.wrap(x, self.width)?;
+        let y = self.boundary_y.wrap(y, self.height)?;
+        let z = self.boundary_z.wrap(z, self.depth)?;
+        Some(x + (y + z*self.height)*self.width)
     }
 
+    /// Nucleates at a random cell, grains are chosen in proportion to their nucleation density
+    /// (uniformly if all densities are zero)
     fn random_activate<T: Rng>(&mut self, rng: &mut T, field: f32) -> usize{
-        let i = rng.gen_range(0..self.width*self.height);
-        if field * (self.cells[i].get_polarization() as f32) < 0.0{
-            self.activate_cell(i, field, &mut Default::default())
-        }
+        let i = loop{
+            let i = rng.gen_range(0..self.cell_count());
+            let Some(m) = &self.grain_map else {break i};
+            let max = m.grains.iter().fold(0.0, |max: f32, g| max.max(g.nucleation));
+            if max <= 0.0 || rng.gen::<f32>()*max < m.grains[self.grain(i)].nucleation{
+                break i;
+            }
+        };
+        self.nucleate(i, field, rng);
         i
     }
 
+    /// Switches cell `i` along `field` if it is against it and activates its neighbours
+    fn nucleate<T: Rng>(&mut self, i: usize, field: f32, rng: &mut T){
+        let variant = self.cells[i].variant;
+        if self.variants.opposes(variant, field){
+            self.activate_cell(i, field, self.variants.aligned(variant, field), &Default::default(), rng)
+        }
+    }
+
     /// Field there is used to activate neighbours (check whether they are already properly polarised)
     /// Old active data is used to transfer neighbour weight from previous iteration 
-    fn activate_cell(&mut self, cell_id: usize, electric_field: f32, old_active: &FnvHashMap<usize, f32>){
-        let activation = electric_field > 0.0;
-        assert_eq!(!self.cells[cell_id].polarization, activation);
+    fn activate_cell<T: Rng>(&mut self, cell_id: usize, electric_field: f32, variant: u8, old_active: &ActiveMap, rng: &mut T){
+        let old = self.cells[cell_id].variant;
+        assert!(self.variants.projection(variant)*electric_field > self.variants.projection(old)*electric_field);
 
-        self.cells[cell_id].polarization = activation;
-        self.polarization_counter += Self::bool2charge(activation);
+        self.switch(cell_id, variant, rng);
         self.activate_neighbours(cell_id, electric_field, old_active);
     }
 
+    /// Puts cell `i` into `variant`, counts the polarization change and lets [`CellBox::fatigue`] pin or release it
+    fn switch<T: Rng>(&mut self, i: usize, variant: u8, rng: &mut T){
+        let old = replace(&mut self.cells[i].variant, variant);
+        self.polarization_counter += self.variants.charge(old, variant)*self.axis(i) as f64;
+        if let (Some(f), Some(pinned)) = (&self.fatigue, self.pinned.get_mut(i)){
+            *pinned = f.switched(*pinned, rng);
+        }
+    }
+
 
-    fn activate_neighbours(&mut self, cell_id: usize, electric_field: f32, old_active: &FnvHashMap<usize, f32>){
-        for (i, n_id) in self.get_neighbours(cell_id).into_iter().enumerate().filter_map(|(i, j)| j.and_then(|v| Some((i, v)))){
-            if electric_field * ((self.cells[n_id].get_polarization() as f32)) < 0.0{
-                let pol_coeff = match i {
-                    0|3 => self.y_spread,
-                    1|2 => self.x_spread,
-                    _ => unreachable!()
-                };
-                let e = self.active.entry(n_id).or_default();
-                *e += pol_coeff + old_active.get(&n_id).unwrap_or(&mut 0.0);
+    fn activate_neighbours(&mut self, cell_id: usize, electric_field: f32, old_active: &ActiveMap){
+        let (x, y, z) = self.index2coord(cell_id);
+        let parity = self.geometry.parity(x, y);
+        let grain = self.grain(cell_id);
+        for k in 0..self.offsets[grain][parity].len(){
+            let (dx, dy, dz, mut pol_coeff) = self.offsets[grain][parity][k];
+            if let Some(a) = &self.anisotropy{
+                pol_coeff *= a.factor(dx, dy, electric_field);
+            }
+            if self.boundary_x.mirrors(x, dx, self.width) || self.boundary_y.mirrors(y, dy, self.height) || self.boundary_z.mirrors(z, dz, self.depth){
+                for n_id in self.receivers((x, y, z), (dx, dy, dz)){
+                    self.pass_weight(cell_id, n_id, pol_coeff, electric_field, old_active);
+                }
+            }
+            else if let Some(n_id) = self.coord2index((x as i32 + dx, y as i32 + dy, z as i32 + dz)){
+                self.pass_weight(cell_id, n_id, pol_coeff, electric_field, old_active);
+            }
+        }
+    }
+
+    /// Cells receiving what the cell at `(x, y, z)` passes by `(dx, dy, dz)`, a cell is listed once per way it is reached
+    fn receivers(&self, (x, y, z): Coord, (dx, dy, dz): (i32, i32, i32)) -> Vec<usize>{
+        let xs = self.boundary_x.receivers(x, dx, self.width);
+        let ys = self.boundary_y.receivers(y, dy, self.height);
+        let zs = self.boundary_z.receivers(z, dz, self.depth);
+        let mut receivers = vec![];
+        for &z in &zs{
+            for &y in &ys{
+                receivers.extend(xs.iter().map(|&x| x + (y + z*self.height)*self.width));
             }
         }
+        receivers
     }
 
-    fn step<T: Rng>(&mut self, electric_field: f32, tend: &FieldTend, rng: &mut T){
-        let new_vec = FnvHashMap::with_capacity_and_hasher((self.active.len() as f32 *1.4) as usize, Default::default());
+    /// Adds the weight `pol_coeff` passed from a switched cell to its neighbour `n_id` if the neighbour can still switch
+    fn pass_weight(&mut self, cell_id: usize, n_id: usize, pol_coeff: f32, electric_field: f32, old_active: &ActiveMap){
+        if self.variants.opposes(self.cells[n_id].variant, electric_field){
+            let mut bond = (self.quenched(cell_id).bond + self.quenched(n_id).bond)/2.0;
+            if self.grain(n_id) != self.grain(cell_id){
+                bond *= self.grains.transmission;
+            }
+            let e = self.active.entry(n_id).or_default();
+            *e += pol_coeff*bond + old_active.get(&n_id).unwrap_or(&0.0);
+        }
+    }
+
+    /// Switches cells along their local field at once, see [`Relaxation`]
+    fn relax<T: Rng>(&mut self, electric_field: f32, barrier: f32, temperature: f32, rng: &mut T){
+        let Some(relaxation) = &self.relaxation else {return};
+        let nucleation = relaxation.nucleation;
+        let depolarizing = self.depolarizing_field();
+        let layer = self.width*self.height;
+        let mut switched = vec![];
+        for i in 0..self.cell_count(){
+            let quenched = self.quenched(i);
+            let local = depolarizing.as_ref().map_or(electric_field, |d| electric_field + d[i % layer])*self.axis(i) + quenched.field;
+            if !self.variants.opposes(self.cells[i].variant, local){
+                continue;
+            }
+            // weight from neighbours polarized along the local field
+            let (x, y, z) = self.index2coord(i);
+            let grain = self.grain(i);
+            let mut weight = nucleation;
+            for &(dx, dy, dz, mut pol_coeff) in &self.offsets[grain][self.geometry.parity(x, y)]{
+                let Some(n) = self.coord2index((x as i32 + dx, y as i32 + dy, z as i32 + dz)) else {continue};
+                if self.variants.projection(self.cells[n].variant)*local <= 0.0{
+                    continue;
+                }
+                if let Some(a) = &self.anisotropy{
+                    pol_coeff *= a.factor(-dx, -dy, local);
+                }
+                let mut bond = (quenched.bond + self.quenched(n).bond)/2.0;
+                if self.grain(n) != grain{
+                    bond *= self.grains.transmission;
+                }
+                weight += pol_coeff*bond;
+            }
+            let shape = self.activation_func.func(weight, local.abs(), temperature);
+            if let Some(variant) = self.cells[i].activation(shape, local, barrier*quenched.barrier*self.pinning(i), rng, &self.variants){
+                switched.push((i, variant));
+            }
+        }
+        for (i, variant) in switched{
+            self.switch(i, variant, rng);
+        }
+    }
+
+    /// Gives every cell a random variant, as in the paraelectric phase; the walls between them stay active for cooling
+    fn randomize<T: Rng>(&mut self, rng: &mut T){
+        let count = self.variants.count() as u8;
+        for c in self.cells.iter_mut(){
+            c.variant = rng.gen_range(0..count);
+        }
+        self.recount();
+        self.activate_walls();
+    }
+
+    /// Sums polarization of all cells anew
+    fn recount(&mut self){
+        self.polarization_counter = (0..self.cells.len()).map(|i| self.variants.charge(0, self.cells[i].variant)*self.axis(i) as f64).sum();
+    }
+
+    /// Stores `map` resampled to the lattice, it is applied at the next reset; a malformed map is an error
+    pub fn import_map(&mut self, kind: MapKind, map: CellMap) -> Result<(), String>{
+        map.check()?;
+        *self.maps.get_mut(kind) = Some(map.resample(self.width, self.height));
+        Ok(())
+    }
+
+    /// Sets initial polarization and pinning from the imported maps; maps of another size are resampled
+    fn apply_maps(&mut self){
+        let layer = self.width*self.height;
+        if let Some(map) = &self.maps.pinning{
+            let map = map.resample(self.width, self.height);
+            if self.quenched.is_empty(){
+                self.quenched = vec![Quenched::default(); self.cell_count()];
+            }
+            for (i, q) in self.quenched.iter_mut().enumerate(){
+                q.barrier *= 1.0 + self.maps.pinning_scale*map.values[i % layer];
+            }
+        }
+        if let Some(map) = &self.maps.polarization{
+            let map = map.resample(self.width, self.height);
+            let up = self.variants.aligned(0, 1.0);
+            for (i, c) in self.cells.iter_mut().enumerate(){
+                c.variant = if map.values[i % layer] > 0.5 {up} else {0};
+            }
+            self.recount();
+            self.activate_walls();
+        }
+    }
+
+    /// Activates every cell with neighbours of the opposite polarization, so walls of a set state can move
+    fn activate_walls(&mut self){
+        self.active.clear();
+        for i in 0..self.cell_count(){
+            let projection = self.variants.projection(self.cells[i].variant);
+            if projection != 0.0{
+                self.activate_neighbours(i, projection.signum(), &Default::default());
+            }
+        }
+    }
+
+    /// Draws quenched values of every cell from [`CellBox::disorder`]
+    pub fn quench<T: Rng>(&mut self, rng: &mut T){
+        self.quenched = if self.disorder.is_clean() {vec![]} else {self.disorder.draw(self.cell_count(), rng)};
+    }
+
+    fn quenched(&self, i: usize) -> Quenched{
+        self.quenched.get(i).copied().unwrap_or_default()
+    }
+
+    /// Pins cells at reset according to [`CellBox::fatigue`]
+    pub fn pin<T: Rng>(&mut self, rng: &mut T){
+        self.pinned = self.fatigue.as_ref().map_or(vec![], |f| f.draw(self.cell_count(), rng));
+    }
+
+    /// Factor of the activation exponent of cell `i` from its pinning
+    fn pinning(&self, i: usize) -> f32{
+        match (&self.fatigue, self.pinned.get(i)){
+            (Some(f), Some(true)) => f.strength,
+            _ => 1.0
+        }
+    }
+
+    /// Share of pinned cells
+    pub fn pinned_share(&self) -> f64{
+        self.pinned.iter().filter(|&&p| p).count() as f64/self.cell_count() as f64
+    }
+
+    /// Long-range field at every column of cells, from the polarization averaged through the thickness.
+    /// Reflecting edges are treated as open.
+    pub fn depolarizing_field(&mut self) -> Option<Vec<f32>>{
+        let params = self.depolarization.as_ref()?;
+        let layer = self.width*self.height;
+        let p: Vec<f32> = (0..layer).map(|i| (0..self.depth)
+            .map(|z| self.variants.projection(self.cells[i + z*layer].variant)).sum::<f32>()*self.axis(i)/self.depth as f32).collect();
+        let periodic = (self.boundary_x == Boundary::Periodic, self.boundary_y == Boundary::Periodic);
+        Some(self.dipole.field(&p, self.width, self.height, periodic, params))
+    }
+
+    fn step<T: Rng>(&mut self, electric_field: f32, tend: &FieldTend, barrier: f32, temperature: f32, rng: &mut T){
+        if electric_field == 0.0 && *tend == FieldTend::Stable{
+            return; // no cell can switch, the walls wait for the field with their weights
+        }
+        let new_vec = ActiveMap::with_capacity_and_hasher((self.active.len() as f32 *1.4) as usize, Default::default());
         // create map for new iteration
 
         let active = replace(&mut self.active, new_vec); // save old active cells
 
         if let FieldTend::Stable = tend{ // field is stable
+            let depolarizing = self.depolarizing_field();
+            let layer = self.width*self.height;
             for (&cell_id, &cell_accum) in active.iter(){ // iterate over *old cells and weights*
 
-                if electric_field * (self.cells[cell_id].get_polarization() as f32) < 0.0{
-                    if self.cells[cell_id].activation(cell_accum, electric_field, rng, &self.activation_func){
-                        assert_eq!(electric_field < 0.0, self.cells[cell_id].polarization);
-
-                        self.activate_cell(cell_id, electric_field, &active); // reverse and activate neighbours
+                if self.variants.opposes(self.cells[cell_id].variant, electric_field){
+                    // cells where the local field turned against the applied one wait
+                    let quenched = self.quenched(cell_id);
+                    let local = depolarizing.as_ref().map_or(electric_field, |d| electric_field + d[cell_id % layer])*self.axis(cell_id) + quenched.field;
+                    let switched = if local*electric_field.signum() > 0.0{
+                        self.cells[cell_id].activation(self.activation_func.func(cell_accum, local.abs(), temperature), local, barrier*quenched.barrier*self.pinning(cell_id), rng, &self.variants)
+                    }
+                    else{
+                        None
+                    };
+                    if let Some(variant) = switched{
+                        self.activate_cell(cell_id, electric_field, variant, &active, rng); // reverse and activate neighbours
+                        if self.variants.opposes(variant, electric_field){
+                            // intermediate variant keeps switching
+                            let e = self.active.entry(cell_id).or_default();
+                            *e += cell_accum;
+                        }
                     }
                     else{
                         let e = self.active.entry(cell_id).or_default();
@@ -351,3 +1723,348 @@ impl CellBox{
         //println!("{:?}, {:?}", active, self.active);
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{analysis::{self, LoopMetrics}, checkpoint::SimRng};
+
+    /// Metrics of the P–E loop over the last of three triangle periods
+    fn loop_metrics(imprint: f32) -> LoopMetrics{
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(60, 60);
+        sim.gen.shape = Waveform::Triangle;
+        sim.gen.amplitude = 1.5;
+        sim.imprint.field = imprint;
+        sim.reset(&mut rng);
+
+        let mut samples = vec![];
+        for _ in 0..3*sim.gen.period(){
+            sim.step(&mut rng);
+            samples.push((sim.get_field() as f64, sim.get_polarization()));
+        }
+        let cycles = analysis::split_cycles(&samples);
+        LoopMetrics::new(cycles[cycles.len() - 2])
+    }
+
+    #[test]
+    fn imprint_shifts_loop(){
+        let (free, shifted) = (loop_metrics(0.0), loop_metrics(0.1));
+        let (free, shifted) = (free.imprint().unwrap(), shifted.imprint().unwrap());
+        // the built-in field adds to the applied one, so the loop moves towards negative applied fields
+        assert!((free - shifted - 0.1).abs() < 0.03, "imprint readout {free} without and {shifted} with the built-in field");
+    }
+
+    /// Lattice whose left half is polarized up by a map, under a constant field `field` and without germs
+    fn half_up(field: f32) -> (Simulation, SimRng){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(40, 40);
+        sim.germs = GermGenesis::StartFixed { number: 0, fixed: vec![] };
+        sim.gen.shape = Waveform::PiecewiseLinear { points: vec![(0, field), (1, field)] };
+        sim.gen.amplitude = 1.0;
+        sim.cells.import_map(MapKind::Polarization, CellMap { width: 2, height: 1, values: vec![1.0, 0.0] }).unwrap();
+        sim.reset(&mut rng);
+        (sim, rng)
+    }
+
+    #[test]
+    fn walls_of_mapped_state_move(){
+        for field in [-0.4, 0.4]{
+            let (mut sim, mut rng) = half_up(field);
+            assert_eq!(sim.get_polarization(), 0.5);
+            for _ in 0..200{
+                sim.step(&mut rng);
+            }
+            let p = sim.get_polarization();
+            assert!(if field > 0.0 {p > 0.6} else {p < 0.4}, "polarization {p} in field {field}");
+        }
+    }
+
+    #[test]
+    fn walls_wait_through_zero_field(){
+        let (mut sim, mut rng) = half_up(0.4);
+        sim.gen.shape = Waveform::PiecewiseLinear { points: vec![(0, 0.0), (20, 0.0), (21, 0.4), (300, 0.4)] };
+        sim.reset(&mut rng);
+        for _ in 0..200{
+            sim.step(&mut rng);
+        }
+        let p = sim.get_polarization();
+        assert!(p > 0.6, "polarization {p} after a pause at zero field");
+    }
+
+    #[test]
+    fn walls_after_cooling_move(){
+        let (mut sim, mut rng) = half_up(0.4);
+        // paraelectric at the first tick only
+        sim.thermostat = Thermostat { ramp: vec![(0, 2.0), (1, 1.0)], curie: Some(1.5), ..Default::default() };
+        sim.reset(&mut rng);
+        sim.step(&mut rng);
+        let cooled = sim.get_polarization();
+        assert!((cooled - 0.5).abs() < 0.1, "polarization {cooled} after the paraelectric phase");
+        for _ in 0..200{
+            sim.step(&mut rng);
+        }
+        assert!(sim.get_polarization() > cooled + 0.1);
+    }
+
+    #[test]
+    fn map_germs_of_both_signs(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut cells = CellBox::new(4, 1);
+        cells.import_map(MapKind::Germs, CellMap { width: 4, height: 1, values: vec![1.0, 0.5, 0.0, 0.8] }).unwrap();
+        cells.import_map(MapKind::Polarization, CellMap { width: 4, height: 1, values: vec![0.0, 1.0, 1.0, 0.0] }).unwrap();
+        assert!(cells.import_map(MapKind::Pinning, CellMap { width: 4, height: 1, values: vec![0.0; 3] }).is_err());
+        cells.clear();
+        cells.apply_maps();
+        let germs = GermGenesis::new_fixed(&mut cells, &mut rng, 0);
+        assert!(matches!(germs, GermGenesis::StartFixed { fixed, .. } if fixed == [0, 1, 3]));
+        let up = |v: u8| cells.variants.projection(v) > 0.0;
+        assert_eq!(cells.cells.iter().map(|c| up(c.variant)).collect::<Vec<_>>(), [true, false, true, true]);
+    }
+
+    #[test]
+    fn fixed_germs_on_new_lattice(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(30, 30);
+        sim.germs = GermGenesis::new_fixed(&mut sim.cells, &mut rng, 5);
+        assert!(sim.get_polarization() > 0.0);
+        assert!(!sim.cells.active.is_empty());
+    }
+
+    #[test]
+    fn offsets_follow_spreads(){
+        let mut cells = CellBox::new(10, 10);
+        let built = cells.offsets.clone();
+        cells.refresh_offsets();
+        assert_eq!(cells.offsets, built);
+        cells.x_spread = 2.0;
+        cells.refresh_offsets();
+        assert!(cells.offsets[0][0].contains(&(1, 0, 0, 2.0)), "{:?}", cells.offsets);
+    }
+
+    #[test]
+    fn grained_state_in_single_crystal(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut grained = Simulation::new(20, 20);
+        grained.cells.grains.count = 4;
+        grained.cells.grains.spread = crate::disorder::Distribution::Uniform { low: 0.5, high: 1.5 };
+        grained.reset(&mut rng);
+        let mut sim = Simulation::new(20, 20);
+        sim.step(&mut rng);
+        sim.set_state(grained.state()).unwrap();
+        assert_eq!(sim.cells.offsets.len(), 4);
+        for _ in 0..50{
+            sim.step(&mut rng);
+        }
+    }
+
+    #[test]
+    fn full_switch_saturates(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(20, 20);
+        sim.cells.variants = Variants::Rhombohedral { rate_71: 1.0, rate_109: 0.5, rate_180: 0.1 };
+        sim.cells.grains.count = 4;
+        sim.reset(&mut rng);
+        assert_eq!(sim.get_polarization(), 0.0);
+        for i in 0..sim.cells.cell_count(){
+            let v = sim.cells.cells[i].variant;
+            sim.cells.switch(i, sim.cells.variants.aligned(v, 1.0), &mut rng);
+        }
+        assert!((sim.get_polarization() - 1.0).abs() < 1e-9, "{}", sim.get_polarization());
+        assert!((sim.cells.layer_polarization(0) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn transition_rates(){
+        let tetragonal = Variants::Tetragonal { rate_180: 0.2, rate_90: 1.0 };
+        let from_down: Vec<_> = tetragonal.transitions(0, 1.0).collect();
+        assert_eq!(from_down, [(1, 0.2), (2, 1.0), (3, 1.0), (4, 1.0), (5, 1.0)]);
+        assert_eq!(tetragonal.transitions(2, 1.0).collect::<Vec<_>>(), [(1, 1.0)]);
+        assert_eq!(tetragonal.transitions(1, 1.0).count(), 0);
+        assert_eq!(tetragonal.transitions(1, -1.0).collect::<Vec<_>>(), [(0, 0.2), (2, 1.0), (3, 1.0), (4, 1.0), (5, 1.0)]);
+
+        let rhombohedral = Variants::Rhombohedral { rate_71: 1.0, rate_109: 0.5, rate_180: 0.1 };
+        assert_eq!(rhombohedral.label(0), "−x−y−z");
+        let from_down: Vec<_> = rhombohedral.transitions(0, 1.0).collect();
+        assert_eq!(from_down, [(1, 1.0), (3, 0.5), (5, 0.5), (7, 0.1)]);
+        assert_eq!(rhombohedral.transitions(6, -1.0).count(), 0);
+        assert_eq!(rhombohedral.aligned(6, 1.0), 7);
+    }
+
+    #[test]
+    fn nucleation_laws(){
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+        let merz = NucleationLaw::Merz { alpha: 1.0 };
+        assert_eq!(merz.factor(0.0), 0.0);
+        assert!(close(merz.factor(1.0), (-1f32).exp()));
+        assert!(close(merz.factor(-2.0), (-0.5f32).exp()));
+        assert!(merz.factor(0.5) < merz.factor(1.0) && merz.factor(1.0) < merz.factor(4.0) && merz.factor(4.0) < 1.0);
+
+        let power = NucleationLaw::Power { field: 0.5, exponent: 2.0 };
+        assert_eq!(power.factor(0.0), 0.0);
+        assert!(close(power.factor(1.0), 4.0));
+        assert!(close(power.factor(-0.25), 0.25));
+        assert_eq!(NucleationLaw::Power { field: 0.0, exponent: 2.0 }.factor(1.0), 0.0);
+
+        // nuclei of one tick follow the law
+        let nuclei = |field: f32| {
+            let mut rng = SimRng::seed_from_u64(0);
+            let mut cells = CellBox::new(100, 100);
+            let mut germs = GermGenesis::FieldDriven { rate: 0.01, law: power, decay: 0.0, since: 0 };
+            germs.tick(field, &mut cells, &mut rng);
+            cells.polarization_counter
+        };
+        let (weak, strong) = (nuclei(0.25), nuclei(0.5));
+        assert!((weak - 25.0).abs() < 15.0 && (strong - 100.0).abs() < 30.0, "{weak} and {strong} nuclei");
+    }
+
+    #[test]
+    fn anisotropy_follows_direction_and_sign(){
+        let a = Anisotropy { up: [2.0, 0.5, 1.0, 3.0], down: Some([0.5, 2.0, 1.0, 1.0]) };
+        assert_eq!(a.factor(1, 0, 1.0), 2.0);
+        assert_eq!(a.factor(-2, 0, 1.0), 0.5);
+        assert_eq!(a.factor(0, -1, 1.0), 3.0);
+        assert_eq!(a.factor(1, 1, 1.0), 1.5);
+        assert_eq!(a.factor(0, 0, 1.0), 1.0);
+        assert_eq!(a.factor(1, 0, -1.0), 0.5);
+        assert_eq!(a.factor(-1, 0, -1.0), 2.0);
+        assert_eq!(Anisotropy { down: None, ..a.clone() }.factor(1, 0, -1.0), 2.0);
+
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut cells = CellBox::new(5, 5);
+        cells.stencil = Stencil::VonNeumann { range: 1 };
+        cells.anisotropy = Some(a);
+        cells.update_offsets();
+        cells.activate_cell(12, 1.0, 1, &Default::default(), &mut rng);
+        assert_eq!(cells.active.get(&13), Some(&(2.0*cells.x_spread)));
+        assert_eq!(cells.active.get(&11), Some(&(0.5*cells.x_spread)));
+        assert_eq!(cells.active.get(&7), Some(&(3.0*cells.y_spread)));
+    }
+
+    #[test]
+    fn hexagonal_and_triangular_neighbours(){
+        for (geometry, count) in [(Geometry::Hexagonal, 6), (Geometry::Triangular, 3)]{
+            for (x, y) in [(2, 2), (3, 2), (2, 3), (3, 3)]{
+                let neighbours = geometry.neighbours(geometry.parity(x, y));
+                assert_eq!(neighbours.len(), count);
+                // every neighbour sees the cell back across the same direction
+                for &(dx, dy, d) in neighbours{
+                    let (nx, ny) = ((x as i32 + dx) as usize, (y as i32 + dy) as usize);
+                    assert!(geometry.neighbours(geometry.parity(nx, ny)).contains(&(-dx, -dy, d)), "{:?} at {:?}", geometry, (x, y));
+                }
+                for d in 0..3{
+                    let along = neighbours.iter().filter(|n| n.2 == d).count();
+                    assert_eq!(along, count/3, "{:?} direction {}", geometry, d);
+                }
+            }
+        }
+
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut cells = CellBox::new(5, 5);
+        cells.geometry = Geometry::Hexagonal;
+        cells.lattice_spread = [1.0, 0.5, 0.25];
+        cells.update_offsets();
+        cells.activate_cell(12, 1.0, 1, &Default::default(), &mut rng);
+        let mut active: Vec<_> = cells.active.iter().map(|(&i, &w)| (i, w)).collect();
+        active.sort_by_key(|a| a.0);
+        assert_eq!(active, [(6, 0.25), (7, 0.5), (11, 1.0), (13, 1.0), (16, 0.5), (17, 0.25)]);
+    }
+
+    #[test]
+    fn layers_polarize_separately(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(4, 4);
+        sim.germs = GermGenesis::StartRandom { number: 0 };
+        sim.cells.depth = 3;
+        sim.reset(&mut rng);
+
+        // a switched cell passes weight to the cells above and below it, not further
+        sim.cells.activate_cell(21, 1.0, 1, &Default::default(), &mut rng);
+        let z_spread = sim.cells.z_spread;
+        assert_eq!(sim.cells.active.get(&5), Some(&z_spread));
+        assert_eq!(sim.cells.active.get(&37), Some(&z_spread));
+        assert!(sim.cells.active.keys().all(|&i| i == 5 || i == 37 || (16..32).contains(&i)), "{:?}", sim.cells.active);
+        assert_eq!(sim.cells.layer_polarization(1), 1.0/16.0);
+
+        for i in 16..32{
+            sim.cells.switch(i, 1, &mut rng);
+        }
+        let layers: Vec<f64> = (0..3).map(|z| sim.cells.layer_polarization(z)).collect();
+        assert_eq!(layers, [0.0, 1.0, 0.0]);
+        assert!((sim.get_polarization() - 1.0/3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn germs_are_pinned_by_fatigue(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut cells = CellBox::new(30, 30);
+        cells.fatigue = Some(Fatigue { pin: 1.0, ..Default::default() });
+        cells.pin(&mut rng);
+        GermGenesis::new_fixed(&mut cells, &mut rng, 5);
+        // down germs do not switch the initial state
+        assert_eq!(cells.pinned_share(), 5.0/900.0);
+    }
+
+    #[test]
+    fn boundaries_wrap(){
+        assert_eq!(Boundary::Open.wrap(-1, 5), None);
+        assert_eq!(Boundary::Open.wrap(4, 5), Some(4));
+        assert_eq!(Boundary::Periodic.wrap(-1, 5), Some(4));
+        assert_eq!(Boundary::Periodic.wrap(7, 5), Some(2));
+        assert_eq!(Boundary::Reflecting.wrap(-1, 5), Some(1));
+        assert_eq!(Boundary::Reflecting.wrap(5, 5), Some(3));
+        assert_eq!(Boundary::Reflecting.wrap(9, 5), Some(1));
+        assert_eq!(Boundary::Reflecting.wrap(-3, 1), Some(0));
+
+        for b in [Boundary::Open, Boundary::Periodic, Boundary::Reflecting]{
+            for n in 1..6{
+                for c in 0..n{
+                    for d in -7..=7{
+                        let mut receivers = b.receivers(c, d, n);
+                        receivers.sort();
+                        let expected: Vec<usize> = (0..n).filter(|&t| b.wrap(t as i32 - d, n) == Some(c)).collect();
+                        assert_eq!(receivers, expected, "{:?} c = {} d = {} n = {}", b, c, d, n);
+                        if !b.mirrors(c, d, n){
+                            assert_eq!(receivers, b.wrap(c as i32 + d, n).into_iter().collect::<Vec<_>>());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reflecting_edge_receives_twice(){
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut cells = CellBox::new(5, 1);
+        cells.stencil = Stencil::VonNeumann { range: 1 };
+        cells.boundary_x = Boundary::Reflecting;
+        cells.update_offsets();
+        cells.activate_cell(1, 1.0, 1, &Default::default(), &mut rng);
+        assert_eq!(cells.active.get(&0), Some(&(2.0*cells.x_spread)));
+        assert_eq!(cells.active.get(&2), Some(&cells.x_spread));
+    }
+
+    /// Ticks of the reversals over two periods of a triangle wave of amplitude 1 shifted by `offset`
+    fn reversals(offset: f32) -> Vec<(u32, FieldTend)>{
+        let mut gen = Simulation::new(1, 1).gen;
+        gen.shape = Waveform::Triangle;
+        (gen.time_up, gen.time_down, gen.amplitude) = (8, 8, 1.0);
+        gen.reset();
+        (0..2*gen.period()).filter_map(|t| {
+            let (_, tend) = gen.field(offset);
+            gen.tick(offset);
+            (tend != FieldTend::Stable).then_some((t, tend))
+        }).collect()
+    }
+
+    #[test]
+    fn reversals_follow_offset(){
+        use FieldTend::*;
+        assert_eq!(reversals(0.0), [(0, ReverseUp), (8, ReverseDown), (16, ReverseUp), (24, ReverseDown)]);
+        // with the offset of 0.5 the sign changes only where the half-waves pass -0.5
+        assert_eq!(reversals(0.5), [(0, ReverseUp), (10, ReverseDown), (14, ReverseUp), (26, ReverseDown), (30, ReverseUp)]);
+    }
+}
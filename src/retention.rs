@@ -0,0 +1,211 @@
+//! Retention: switching along the local field while the applied one is weak (backswitching and relaxation)
+//! and the measurement of the polarization left after a written state is held.
+
+use std::mem::replace;
+
+use rand::Rng;
+
+use crate::physics::{Simulation, Waveform};
+
+/// Switching of cells along their local field (depolarizing, quenched and built-in) while the applied field is weak.
+/// A cell switches with the probability of the activation law, its weight is `nucleation`
+/// plus the spreads from neighbours already polarized along the local field.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Relaxation {
+    /// Largest magnitude of the applied field that counts as a hold
+    pub below: f32,
+    /// Weight of a cell without such neighbours, lets new domains nucleate
+    pub nucleation: f32,
+}
+
+impl Default for Relaxation {
+    fn default() -> Self {
+        Self {
+            below: 0.05,
+            nucleation: 0.3,
+        }
+    }
+}
+
+/// Parameters of the retention measurement
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Retention {
+    /// Field of the writing pulse, its sign sets the written state
+    pub field: f32,
+    /// Ticks of the writing pulse
+    pub write: u32,
+    /// Field during the hold
+    pub hold_field: f32,
+    /// Ticks of the hold
+    pub hold: u32,
+    /// Samples per decade of the hold time
+    pub per_decade: u32,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            field: 1.0,
+            write: 500,
+            hold_field: 0.0,
+            hold: 100_000,
+            per_decade: 10,
+        }
+    }
+}
+
+/// Running retention measurement. Replaces the field waveform of the simulation until it is finished.
+#[derive(Debug)]
+pub struct RetentionRun {
+    /// Parameters of the measurement
+    pub protocol: Retention,
+    /// Polarization at the end of the writing pulse
+    pub written: Option<f64>,
+    /// `(ticks of hold, polarization)`, logarithmically spaced
+    pub points: Vec<(u32, f64)>,
+    t: u32,                              // tick from the start of the pulse
+    samples: u32,                        // taken or skipped so far
+    saved: Option<(Waveform, f32, f32)>, // user waveform, amplitude and bias
+}
+
+impl RetentionRun {
+    /// Measurement that starts at the next step
+    pub fn new(protocol: Retention) -> Self {
+        Self {
+            protocol,
+            written: None,
+            points: vec![],
+            t: 0,
+            samples: 0,
+            saved: None,
+        }
+    }
+
+    /// Whether the hold is over or the run was stopped by [`RetentionRun::restore`]
+    pub fn finished(&self) -> bool {
+        self.t > self.protocol.write + self.protocol.hold || (self.t > 0 && self.saved.is_none())
+    }
+
+    /// Makes one simulation step. Returns `true` when the hold is over
+    /// and the waveform of the simulation is restored.
+    pub fn step<T: Rng>(&mut self, sim: &mut Simulation, rng: T) -> bool {
+        if self.finished() {
+            return true;
+        }
+        let (write, hold) = (self.protocol.write, self.protocol.hold);
+
+        if self.t == 0 {
+            // the last point lies beyond the end, so the period does not wrap around
+            let (e, h) = (self.protocol.field, self.protocol.hold_field);
+            let shape = Waveform::PiecewiseLinear {
+                points: vec![(0, e), (write, e), (write + 1, h), (write + hold + 2, h)],
+            };
+
+            let gen = &mut sim.gen;
+            let old_shape = replace(&mut gen.shape, shape);
+            let old_amplitude = replace(&mut gen.amplitude, 1.0);
+            let old_bias = replace(&mut gen.bias, 0.0);
+            self.saved
+                .get_or_insert((old_shape, old_amplitude, old_bias));
+            gen.reset();
+        }
+
+        sim.step(rng);
+        self.t += 1;
+        if self.t == write + 1 {
+            self.written = Some(sim.get_polarization());
+        } else if self.t > write + 1 {
+            // `samples`-th sampling time, several of them may fall on one tick early in the hold
+            let time = |j: u32| {
+                10f64
+                    .powf(j as f64 / self.protocol.per_decade.max(1) as f64)
+                    .ceil() as u32
+            };
+            let held = self.t - write - 1;
+            if time(self.samples) <= held {
+                self.points.push((held, sim.get_polarization()));
+                while time(self.samples) <= held {
+                    self.samples += 1;
+                }
+            }
+        }
+
+        if self.finished() {
+            self.restore(sim);
+            return true;
+        }
+        false
+    }
+
+    /// Share of the written polarization left after each sampled hold time, as `(log10 of ticks, share)`.
+    /// Polarizations are counted from the unpolarized state, so 0 means the memory is lost;
+    /// if the pulse left the lattice unpolarized, they are given as they are.
+    pub fn retained(&self) -> Vec<(f64, f64)> {
+        let Some(written) = self.written else {
+            return vec![];
+        };
+        let m = match 2.0 * written - 1.0 {
+            m if m.abs() < 1e-6 => 1.0,
+            m => m,
+        };
+        self.points
+            .iter()
+            .map(|&(t, p)| ((t as f64).log10(), (2.0 * p - 1.0) / m))
+            .collect()
+    }
+
+    /// Gives the simulation its own waveform back and stops the run; the measured points are kept
+    pub fn restore(&mut self, sim: &mut Simulation) {
+        if let Some((shape, amplitude, bias)) = self.saved.take() {
+            let gen = &mut sim.gen;
+            gen.shape = shape;
+            gen.amplitude = amplitude;
+            gen.bias = bias;
+            gen.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::checkpoint::SimRng;
+
+    #[test]
+    fn samples_are_log_spaced() {
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut sim = Simulation::new(20, 20);
+        let mut run = RetentionRun::new(Retention {
+            write: 50,
+            hold: 1000,
+            per_decade: 1,
+            ..Default::default()
+        });
+        while !run.step(&mut sim, &mut rng) {}
+        let times: Vec<u32> = run.points.iter().map(|p| p.0).collect();
+        assert_eq!(times, [1, 10, 100, 1000]);
+        assert!(run.written.unwrap() > 0.9);
+        assert_eq!(sim.gen.shape, Waveform::Square);
+    }
+
+    #[test]
+    fn retained_share() {
+        let mut run = RetentionRun::new(Retention::default());
+        assert!(run.retained().is_empty());
+        run.written = Some(0.9);
+        run.points = vec![(1, 0.9), (100, 0.7), (1000, 0.5)];
+        let close =
+            |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9;
+        let retained = run.retained();
+        for (a, b) in retained.iter().zip([(0.0, 1.0), (2.0, 0.5), (3.0, 0.0)]) {
+            assert!(close(*a, b), "{:?}", retained);
+        }
+
+        run.written = Some(0.5);
+        let retained = run.retained();
+        assert!(retained.iter().all(|r| r.1.is_finite()), "{:?}", retained);
+        assert!(close(retained[1], (2.0, 0.4)));
+    }
+}